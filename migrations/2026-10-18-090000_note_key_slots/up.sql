@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE note_key_slots (
+  id SERIAL PRIMARY KEY,
+  note_id VARCHAR(32) NOT NULL REFERENCES notes (id) ON DELETE CASCADE,
+  wrapped_key BYTEA NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX note_key_slots_note_id_idx ON note_key_slots (note_id);
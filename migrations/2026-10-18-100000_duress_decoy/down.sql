@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE note_key_slots
+DROP COLUMN duress;
+
+ALTER TABLE notes
+DROP COLUMN decoy_content,
+DROP COLUMN destroy_on_duress;
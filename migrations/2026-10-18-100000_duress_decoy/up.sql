@@ -0,0 +1,8 @@
+-- Your SQL goes here
+
+ALTER TABLE notes
+ADD decoy_content BYTEA,
+ADD destroy_on_duress BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE note_key_slots
+ADD duress BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE notes
+DROP COLUMN recipient_encryption;
+
+DROP TABLE note_recipients;
+DROP TABLE recipients;
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+
+CREATE TABLE recipients (
+  handle VARCHAR(64) PRIMARY KEY,
+  public_key VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE TABLE note_recipients (
+  note_id VARCHAR(32) NOT NULL REFERENCES notes (id) ON DELETE CASCADE,
+  recipient_handle VARCHAR(64) NOT NULL REFERENCES recipients (handle),
+  PRIMARY KEY (note_id, recipient_handle)
+);
+
+ALTER TABLE notes
+ADD recipient_encryption BOOLEAN NOT NULL DEFAULT false;
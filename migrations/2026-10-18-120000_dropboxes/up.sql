@@ -0,0 +1,13 @@
+-- Your SQL goes here
+
+CREATE TABLE dropboxes (
+  id VARCHAR(32) PRIMARY KEY,
+  recipient_handle VARCHAR(64) REFERENCES recipients (handle),
+  public_key VARCHAR NOT NULL,
+  wrapped_identity BYTEA,
+  content BYTEA,
+  submitted_at TIMESTAMP,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  expires_at TIMESTAMP,
+  delete_after_read INT
+);
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE notes
+ADD frontend_envelope JSONB;
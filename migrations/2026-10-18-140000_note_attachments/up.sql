@@ -0,0 +1,13 @@
+-- Your SQL goes here
+
+CREATE TABLE note_attachments (
+  id SERIAL PRIMARY KEY,
+  note_id VARCHAR(32) NOT NULL REFERENCES notes (id) ON DELETE CASCADE,
+  name VARCHAR(255) NOT NULL,
+  mime_type VARCHAR(255) NOT NULL,
+  size INT NOT NULL,
+  content BYTEA NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX note_attachments_note_id_idx ON note_attachments (note_id);
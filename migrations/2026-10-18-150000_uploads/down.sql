@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+DROP TABLE upload_chunks;
+DROP TABLE uploads;
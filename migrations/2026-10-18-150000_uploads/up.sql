@@ -0,0 +1,17 @@
+-- Your SQL goes here
+
+CREATE TABLE uploads (
+  id VARCHAR(32) PRIMARY KEY,
+  upload_length BIGINT NOT NULL,
+  upload_offset BIGINT NOT NULL DEFAULT 0,
+  metadata VARCHAR,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  expires_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE upload_chunks (
+  upload_id VARCHAR(32) NOT NULL REFERENCES uploads (id) ON DELETE CASCADE,
+  chunk_offset BIGINT NOT NULL,
+  data BYTEA NOT NULL,
+  PRIMARY KEY (upload_id, chunk_offset)
+);
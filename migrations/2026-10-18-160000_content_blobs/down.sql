@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE note_attachments DROP COLUMN content_blob;
+ALTER TABLE notes DROP COLUMN decoy_blob;
+ALTER TABLE notes DROP COLUMN content_blob;
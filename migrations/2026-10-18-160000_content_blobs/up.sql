@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+ALTER TABLE notes ADD COLUMN content_blob VARCHAR;
+ALTER TABLE notes ADD COLUMN decoy_blob VARCHAR;
+ALTER TABLE note_attachments ADD COLUMN content_blob VARCHAR;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE notes DROP COLUMN decoy_format;
+ALTER TABLE notes DROP COLUMN content_format;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE notes ADD COLUMN content_format SMALLINT NOT NULL DEFAULT 0;
+ALTER TABLE notes ADD COLUMN decoy_format SMALLINT NOT NULL DEFAULT 0;
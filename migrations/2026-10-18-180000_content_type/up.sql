@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE notes ADD COLUMN content_type VARCHAR NOT NULL DEFAULT 'text/plain';
+ALTER TABLE notes ADD COLUMN language VARCHAR;
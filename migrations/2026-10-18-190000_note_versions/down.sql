@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+DROP TABLE note_versions;
+ALTER TABLE notes DROP COLUMN edited_at;
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+
+ALTER TABLE notes ADD COLUMN edited_at TIMESTAMP;
+
+CREATE TABLE note_versions (
+  id SERIAL PRIMARY KEY,
+  note_id VARCHAR(32) NOT NULL REFERENCES notes (id) ON DELETE CASCADE,
+  content BYTEA NOT NULL,
+  content_blob VARCHAR,
+  content_format SMALLINT NOT NULL DEFAULT 0,
+  frontend_envelope JSONB,
+  written_at TIMESTAMP NOT NULL,
+  replaced_at TIMESTAMP NOT NULL DEFAULT now(),
+  expires_at TIMESTAMP
+);
+
+CREATE INDEX note_versions_note_id_idx ON note_versions (note_id);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX notes_search_vector_idx;
+ALTER TABLE notes DROP COLUMN search_vector;
+ALTER TABLE notes DROP COLUMN search_text;
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+
+-- plaintext of discoverable notes only, their content may be compressed or
+-- kept in the blob store otherwise
+ALTER TABLE notes ADD COLUMN search_text TEXT;
+
+ALTER TABLE notes ADD COLUMN search_vector TSVECTOR GENERATED ALWAYS AS (
+  setweight(to_tsvector('english', coalesce(title, '')), 'A') ||
+  setweight(to_tsvector('english', coalesce(search_text, '')), 'B')
+) STORED;
+
+CREATE INDEX notes_search_vector_idx ON notes USING GIN (search_vector);
+
+UPDATE notes SET search_text = left(convert_from(content, 'UTF8'), 262144)
+WHERE discoverable
+  AND NOT backend_encryption
+  AND NOT frontend_encryption
+  AND NOT recipient_encryption
+  AND content_blob IS NULL
+  AND content_format = 0;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX notes_title_trgm_idx ON notes USING GIN (title gin_trgm_ops)
+WHERE discoverable
+  AND NOT backend_encryption
+  AND NOT frontend_encryption
+  AND NOT recipient_encryption;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+CREATE TABLE note_tags (
+  note_id VARCHAR(32) NOT NULL REFERENCES notes (id) ON DELETE CASCADE,
+  tag VARCHAR(32) NOT NULL,
+  PRIMARY KEY (note_id, tag)
+);
+
+CREATE INDEX note_tags_tag_idx ON note_tags (tag);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX notes_feed_view_count_idx;
+DROP INDEX notes_feed_expires_at_idx;
+DROP INDEX notes_feed_created_at_idx;
+ALTER TABLE notes DROP COLUMN view_count;
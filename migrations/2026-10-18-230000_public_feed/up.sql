@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+ALTER TABLE notes ADD COLUMN view_count BIGINT NOT NULL DEFAULT 0;
+
+CREATE INDEX notes_feed_created_at_idx ON notes (created_at, id) WHERE discoverable;
+CREATE INDEX notes_feed_expires_at_idx ON notes (expires_at, id) WHERE discoverable AND expires_at IS NOT NULL;
+CREATE INDEX notes_feed_view_count_idx ON notes (view_count, id) WHERE discoverable;
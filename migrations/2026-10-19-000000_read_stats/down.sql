@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE notes DROP COLUMN last_read_at;
+ALTER TABLE notes DROP COLUMN first_read_at;
+ALTER TABLE notes DROP COLUMN metadata_view_count;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+ALTER TABLE notes ADD COLUMN metadata_view_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE notes ADD COLUMN first_read_at TIMESTAMP;
+ALTER TABLE notes ADD COLUMN last_read_at TIMESTAMP;
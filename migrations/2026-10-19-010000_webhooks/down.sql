@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+DROP TABLE webhook_deliveries;
+ALTER TABLE notes DROP COLUMN webhook_secret;
+ALTER TABLE notes DROP COLUMN webhook_url;
@@ -0,0 +1,23 @@
+-- Your SQL goes here
+
+ALTER TABLE notes ADD COLUMN webhook_url VARCHAR;
+ALTER TABLE notes ADD COLUMN webhook_secret VARCHAR;
+
+-- not tied to the note, a note read for the last time is deleted before its
+-- receipt goes out
+CREATE TABLE webhook_deliveries (
+  id SERIAL PRIMARY KEY,
+  note_id VARCHAR(32) NOT NULL,
+  url VARCHAR NOT NULL,
+  secret VARCHAR NOT NULL,
+  event VARCHAR NOT NULL,
+  payload TEXT NOT NULL,
+  attempts INT NOT NULL DEFAULT 0,
+  next_attempt_at TIMESTAMP NOT NULL DEFAULT now(),
+  delivered_at TIMESTAMP,
+  failed_at TIMESTAMP,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX webhook_deliveries_pending_idx ON webhook_deliveries (next_attempt_at)
+WHERE delivered_at IS NULL AND failed_at IS NULL;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+DROP TABLE email_notifications;
+ALTER TABLE notes DROP COLUMN notify_email;
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+
+-- sealed with a key derived from SECRET_KEY, see src/mailer.rs
+ALTER TABLE notes ADD COLUMN notify_email BYTEA;
+
+-- like webhook_deliveries, outlives the note it is about
+CREATE TABLE email_notifications (
+  id SERIAL PRIMARY KEY,
+  note_id VARCHAR(32) NOT NULL,
+  recipient BYTEA NOT NULL,
+  subject VARCHAR NOT NULL,
+  body TEXT NOT NULL,
+  attempts INT NOT NULL DEFAULT 0,
+  next_attempt_at TIMESTAMP NOT NULL DEFAULT now(),
+  sent_at TIMESTAMP,
+  failed_at TIMESTAMP,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX email_notifications_pending_idx ON email_notifications (next_attempt_at)
+WHERE sent_at IS NULL AND failed_at IS NULL;
@@ -0,0 +1,400 @@
+//! Storage for payloads too big to keep inline in Postgres.
+//!
+//! `BLOB_STORE` picks the backend, `filesystem` or `s3`, and leaving it unset
+//! keeps everything inline. Content bigger than `BLOB_THRESHOLD` bytes goes to
+//! the backend, with only its key in the `content_blob` column of the row and
+//! an empty `content`. Blobs stay sealed the same way inline content is.
+//!
+//! Every backend blocks, so handlers reach the store from `web::block`.
+use std::{
+    collections::HashSet,
+    io::{self, Cursor, Read, Write},
+    path::PathBuf,
+    sync::{mpsc, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use diesel::{pg::PgConnection, prelude::*};
+use nanoid::nanoid;
+
+use crate::{
+    errors::ServerError,
+    schema::{note_attachments, note_versions, notes},
+};
+
+// a blob is put before the row pointing at it is inserted, so young blobs
+// without a row aren't orphans yet
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+// pieces of an object that may arrive before the reader asks for them
+const READ_AHEAD: usize = 16;
+
+pub trait BlobStore: Send + Sync {
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), ServerError>;
+    /// Writes a blob as it's read, for payloads too big to hold in memory
+    fn put_reader(&self, key: &str, reader: &mut dyn Read) -> Result<(), ServerError>;
+    fn get(&self, key: &str) -> Result<Vec<u8>, ServerError>;
+    /// Reads a blob as it's needed, for backends that can do better than
+    /// [`BlobStore::get`]
+    fn reader(&self, key: &str) -> Result<Box<dyn Read + Send>, ServerError> {
+        Ok(Box::new(Cursor::new(self.get(key)?)))
+    }
+    /// Deleting a blob that doesn't exist is not an error
+    fn delete(&self, key: &str) -> Result<(), ServerError>;
+    fn keys(&self) -> Result<Vec<String>, ServerError>;
+}
+
+pub struct FileSystem {
+    root: PathBuf,
+}
+
+impl FileSystem {
+    pub fn new(root: PathBuf) -> Result<Self, ServerError> {
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+}
+
+impl BlobStore for FileSystem {
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), ServerError> {
+        // written aside first so a blob is never seen half written
+        let partial = self.root.join(format!(".{key}.partial"));
+        std::fs::write(&partial, bytes)?;
+        std::fs::rename(partial, self.root.join(key))?;
+        Ok(())
+    }
+
+    fn put_reader(&self, key: &str, reader: &mut dyn Read) -> Result<(), ServerError> {
+        let partial = self.root.join(format!(".{key}.partial"));
+        let written = std::fs::File::create(&partial).and_then(|mut file| {
+            io::copy(reader, &mut file)?;
+            file.sync_all()
+        });
+        if let Err(e) = written {
+            std::fs::remove_file(&partial).ok();
+            return Err(e.into());
+        }
+        std::fs::rename(partial, self.root.join(key))?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, ServerError> {
+        Ok(std::fs::read(self.root.join(key))?)
+    }
+
+    fn reader(&self, key: &str) -> Result<Box<dyn Read + Send>, ServerError> {
+        Ok(Box::new(std::fs::File::open(self.root.join(key))?))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), ServerError> {
+        match std::fs::remove_file(self.root.join(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>, ServerError> {
+        let mut keys = vec![];
+        for entry in std::fs::read_dir(&self.root)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.starts_with('.') {
+                keys.push(name);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Any S3-compatible object storage, MinIO included
+pub struct S3 {
+    bucket: Box<s3::Bucket>,
+}
+
+impl S3 {
+    /// Setting `endpoint` switches to path-style requests, which is what
+    /// self-hosted storage like MinIO expects.
+    pub fn new(
+        bucket: &str,
+        region: &str,
+        endpoint: Option<String>,
+        credentials: s3::creds::Credentials,
+    ) -> Result<Self, ServerError> {
+        let bucket = match endpoint {
+            Some(endpoint) => s3::Bucket::new(
+                bucket,
+                s3::Region::Custom {
+                    region: region.to_owned(),
+                    endpoint,
+                },
+                credentials,
+            )?
+            .with_path_style(),
+            None => s3::Bucket::new(
+                bucket,
+                region.parse().map_err(|e| {
+                    log::error!("{e:?}");
+                    ServerError::EnvironmentError
+                })?,
+                credentials,
+            )?,
+        };
+
+        Ok(Self { bucket })
+    }
+}
+
+/// Where an object being downloaded on another thread is written to
+struct PieceSender(mpsc::SyncSender<io::Result<Vec<u8>>>);
+
+impl Write for PieceSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "blob reader is gone"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads an object as it's downloaded, failing if the download does
+struct PieceReader {
+    pieces: mpsc::Receiver<io::Result<Vec<u8>>>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for PieceReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match self.pieces.recv() {
+                Ok(piece) => self.current = Cursor::new(piece?),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+impl BlobStore for S3 {
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), ServerError> {
+        self.bucket.put_object(key, bytes)?;
+        Ok(())
+    }
+
+    /// Sent as a multipart upload when it's big enough to need one
+    fn put_reader(&self, key: &str, mut reader: &mut dyn Read) -> Result<(), ServerError> {
+        self.bucket.put_object_stream(&mut reader, key)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, ServerError> {
+        Ok(self.bucket.get_object(key)?.to_vec())
+    }
+
+    /// Downloads the object on a thread of its own, which stops once the
+    /// reader falls [`READ_AHEAD`] pieces behind
+    fn reader(&self, key: &str) -> Result<Box<dyn Read + Send>, ServerError> {
+        let (sender, pieces) = mpsc::sync_channel(READ_AHEAD);
+        let bucket = self.bucket.clone();
+        let key = key.to_owned();
+        std::thread::spawn(move || {
+            let mut writer = PieceSender(sender);
+            if let Err(e) = bucket.get_object_to_writer(&key, &mut writer) {
+                writer.0.send(Err(io::Error::other(e.to_string()))).ok();
+            }
+        });
+
+        Ok(Box::new(PieceReader {
+            pieces,
+            current: Cursor::new(vec![]),
+        }))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), ServerError> {
+        self.bucket.delete_object(key)?;
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>, ServerError> {
+        Ok(self
+            .bucket
+            .list(String::new(), None)?
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| object.key)
+            .collect())
+    }
+}
+
+struct Store {
+    backend: Box<dyn BlobStore>,
+    threshold: usize,
+}
+
+static STORE: OnceLock<Option<Store>> = OnceLock::new();
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or(default.to_string())
+}
+
+fn store() -> Option<&'static Store> {
+    STORE
+        .get_or_init(|| {
+            let backend: Box<dyn BlobStore> = match std::env::var("BLOB_STORE").ok()?.as_str() {
+                "filesystem" => Box::new(
+                    FileSystem::new(env_or("BLOB_DIR", "blobs").into())
+                        .expect("BLOB_DIR must be a writable directory"),
+                ),
+                "s3" => Box::new(
+                    S3::new(
+                        &std::env::var("S3_BUCKET").expect("S3_BUCKET in .env"),
+                        &env_or("S3_REGION", "us-east-1"),
+                        std::env::var("S3_ENDPOINT").ok(),
+                        s3::creds::Credentials::new(
+                            std::env::var("S3_ACCESS_KEY").ok().as_deref(),
+                            std::env::var("S3_SECRET_KEY").ok().as_deref(),
+                            None,
+                            None,
+                            None,
+                        )
+                        .expect("S3 credentials are missing"),
+                    )
+                    .expect("S3 bucket is misconfigured"),
+                ),
+                other => panic!("BLOB_STORE must be filesystem or s3, not {other}"),
+            };
+
+            Some(Store {
+                backend,
+                threshold: env_or("BLOB_THRESHOLD", "1048576")
+                    .parse::<usize>()
+                    .expect("must be an unsigned number of bytes"),
+            })
+        })
+        .as_ref()
+}
+
+/// Sets the store up from the environment, so a misconfiguration shows up at
+/// startup rather than on the first big note
+pub fn init() {
+    store();
+}
+
+fn new_key() -> String {
+    let since_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    // the creation time leads the key, which is what tells orphans apart from
+    // blobs whose row is still being inserted
+    format!("{}-{}", since_epoch.as_secs(), nanoid!())
+}
+
+/// Moves `bytes` to the blob store when they are over the threshold.
+/// Returns what goes in the `content` column and the blob key, if any.
+pub fn offload(bytes: Vec<u8>) -> Result<(Vec<u8>, Option<String>), ServerError> {
+    match store() {
+        Some(store) if bytes.len() > store.threshold => {
+            let key = new_key();
+            store.backend.put(&key, &bytes)?;
+            Ok((vec![], Some(key)))
+        }
+        _ => Ok((bytes, None)),
+    }
+}
+
+/// Same as [`offload`], streaming what `reader` holds to the store instead of
+/// reading it into memory. Where it goes is decided by `size`, about how much
+/// will be read.
+pub fn offload_reader(
+    mut reader: impl Read,
+    size: usize,
+) -> Result<(Vec<u8>, Option<String>), ServerError> {
+    match store() {
+        Some(store) if size > store.threshold => {
+            let key = new_key();
+            store.backend.put_reader(&key, &mut reader)?;
+            Ok((vec![], Some(key)))
+        }
+        _ => {
+            let mut bytes = Vec::with_capacity(size);
+            reader.read_to_end(&mut bytes)?;
+            Ok((bytes, None))
+        }
+    }
+}
+
+/// The content of a row, wherever it is kept
+pub fn load(content: Vec<u8>, blob: Option<String>) -> Result<Vec<u8>, ServerError> {
+    match (blob, store()) {
+        (Some(key), Some(store)) => store.backend.get(&key),
+        (Some(_), None) => {
+            log::error!("a blob is referenced but BLOB_STORE is not set");
+            Err(ServerError::EnvironmentError)
+        }
+        (None, _) => Ok(content),
+    }
+}
+
+/// Same as [`load`], without reading blobs into memory up front
+pub fn reader(content: Vec<u8>, blob: Option<String>) -> Result<Box<dyn Read + Send>, ServerError> {
+    match (blob, store()) {
+        (Some(key), Some(store)) => store.backend.reader(&key),
+        (blob, _) => Ok(Box::new(Cursor::new(load(content, blob)?))),
+    }
+}
+
+fn is_past_grace_period(key: &str) -> bool {
+    key.split('-')
+        .next()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+        .is_some_and(|created| created + ORPHAN_GRACE_PERIOD <= SystemTime::now())
+}
+
+/// Deletes the blobs no note, attachment or version points at anymore, returning how
+/// many were deleted
+pub fn remove_orphans(connection: &mut PgConnection) -> Result<usize, ServerError> {
+    let store = match store() {
+        Some(store) => store,
+        None => return Ok(0),
+    };
+
+    let mut referenced = notes::table
+        .select(notes::content_blob)
+        .filter(notes::content_blob.is_not_null())
+        .get_results::<Option<String>>(connection)?;
+    referenced.extend(
+        notes::table
+            .select(notes::decoy_blob)
+            .filter(notes::decoy_blob.is_not_null())
+            .get_results::<Option<String>>(connection)?,
+    );
+    referenced.extend(
+        note_attachments::table
+            .select(note_attachments::content_blob)
+            .filter(note_attachments::content_blob.is_not_null())
+            .get_results::<Option<String>>(connection)?,
+    );
+    referenced.extend(
+        note_versions::table
+            .select(note_versions::content_blob)
+            .filter(note_versions::content_blob.is_not_null())
+            .get_results::<Option<String>>(connection)?,
+    );
+    let referenced = referenced.into_iter().flatten().collect::<HashSet<_>>();
+
+    let mut removed = 0;
+    for key in store.backend.keys()? {
+        if !referenced.contains(&key) && is_past_grace_period(&key) {
+            store.backend.delete(&key)?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
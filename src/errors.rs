@@ -7,7 +7,11 @@ pub enum ServerError {
     EnvironmentError,
     R2D2Error,
     TinderCryptError,
+    AgeError,
+    IOError,
     JWTError,
+    MultipartError,
+    BlobStoreError,
     Default,
     GeneralNoAccess,
     BlameUpdate,
@@ -41,6 +45,27 @@ impl From<tindercrypt::errors::Error> for ServerError {
     }
 }
 
+impl From<age::EncryptError> for ServerError {
+    fn from(e: age::EncryptError) -> ServerError {
+        println!("{e:?}");
+        ServerError::AgeError
+    }
+}
+
+impl From<age::DecryptError> for ServerError {
+    fn from(e: age::DecryptError) -> ServerError {
+        println!("{e:?}");
+        ServerError::AgeError
+    }
+}
+
+impl From<std::io::Error> for ServerError {
+    fn from(e: std::io::Error) -> ServerError {
+        println!("{e:?}");
+        ServerError::IOError
+    }
+}
+
 impl From<jsonwebtoken::errors::Error> for ServerError {
     fn from(e: jsonwebtoken::errors::Error) -> Self {
         println!("{e:?}");
@@ -57,6 +82,20 @@ impl From<jsonwebtoken::errors::Error> for ServerError {
     }
 }
 
+impl From<actix_multipart::MultipartError> for ServerError {
+    fn from(e: actix_multipart::MultipartError) -> Self {
+        println!("{e:?}");
+        ServerError::MultipartError
+    }
+}
+
+impl From<s3::error::S3Error> for ServerError {
+    fn from(e: s3::error::S3Error) -> Self {
+        println!("{e:?}");
+        ServerError::BlobStoreError
+    }
+}
+
 impl From<std::string::FromUtf8Error> for ServerError {
     fn from(e: std::string::FromUtf8Error) -> Self {
         println!("{e:?}");
@@ -64,6 +103,20 @@ impl From<std::string::FromUtf8Error> for ServerError {
     }
 }
 
+impl From<syntect::Error> for ServerError {
+    fn from(e: syntect::Error) -> Self {
+        println!("{e:?}");
+        ServerError::Default
+    }
+}
+
+impl From<actix_web::error::BlockingError> for ServerError {
+    fn from(e: actix_web::error::BlockingError) -> Self {
+        log::error!("{e:?}");
+        ServerError::Default
+    }
+}
+
 impl actix_web::error::ResponseError for ServerError {
     fn error_response(&self) -> HttpResponse {
         match self {
@@ -77,9 +130,20 @@ impl actix_web::error::ResponseError for ServerError {
             }
             ServerError::TinderCryptError => HttpResponse::InternalServerError()
                 .body("Library Error: File Decryption Unsucessful"),
+            ServerError::AgeError => HttpResponse::InternalServerError()
+                .body("Library Error: Recipient Encryption Unsuccessful"),
+            ServerError::IOError => {
+                HttpResponse::InternalServerError().body("Server Error: I/O Error.")
+            }
             ServerError::JWTError => {
                 HttpResponse::InternalServerError().body("Library Error: JWT Library Malfunctioned")
             }
+            ServerError::MultipartError => {
+                HttpResponse::BadRequest().body("Irregular form of data: Malformed multipart body")
+            }
+            ServerError::BlobStoreError => {
+                HttpResponse::InternalServerError().body("Server Error: Blob Storage Malfunctioned")
+            }
             ServerError::GeneralNoAccess => HttpResponse::Forbidden().body("Invalid token"),
             // leave this messageless
             ServerError::Default => HttpResponse::InternalServerError().finish(),
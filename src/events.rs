@@ -0,0 +1,486 @@
+//! Things happening to notes, for whoever is following them as they happen.
+//!
+//! Handlers and the cleanup task publish to a bus held in memory, and every
+//! subscriber gets every event, picking out the notes it cares about. Events
+//! sent while nobody is subscribed are dropped.
+//!
+//! With `EVENT_BUS=postgres`, events are also sent to every other instance
+//! sharing the database through `NOTIFY`, and a thread `LISTEN`ing for them
+//! passes theirs on to the subscribers of this one. `EVENT_BUS=redis` does the
+//! same with Redis pub/sub. Each instance tags what it sends with a random id
+//! so it can skip its own events, which it has already delivered.
+//!
+//! The `LISTEN`ing connection takes `sslmode` and `sslrootcert` from
+//! `DATABASE_URL` the way libpq does for every other connection, see
+//! [`listener_config`].
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use diesel::{pg::PgConnection, prelude::*, sql_query, sql_types::Text};
+use nanoid::nanoid;
+use postgres::config::SslMode;
+use postgres::fallible_iterator::FallibleIterator;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+    server::ParsedCertificate,
+    DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::{errors::ServerError, redis_pool, schema::notes};
+
+// events a slow subscriber can fall behind by before it misses some
+const CAPACITY: usize = 1024;
+const CHANNEL: &str = "himitsu_events";
+// how often a quiet listener connection is checked for still being alive
+const LISTEN_PING_INTERVAL: Duration = Duration::from_secs(30);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Read,
+    Edited,
+    Deleted,
+    Expired,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Read => "read",
+            Kind::Edited => "edited",
+            Kind::Deleted => "deleted",
+            Kind::Expired => "expired",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NoteEvent {
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    pub note_id: String,
+    pub at: SystemTime,
+    /// What's left of the note afterwards, nothing once it's gone
+    pub reads_left: Option<i32>,
+    pub expires_at: Option<SystemTime>,
+}
+
+/// What goes out over `NOTIFY`
+#[derive(Deserialize, Serialize)]
+struct Notification {
+    origin: String,
+    event: NoteEvent,
+}
+
+static BUS: OnceLock<broadcast::Sender<NoteEvent>> = OnceLock::new();
+static FAN_OUT: OnceLock<FanOut> = OnceLock::new();
+static INSTANCE: OnceLock<String> = OnceLock::new();
+
+fn bus() -> &'static broadcast::Sender<NoteEvent> {
+    BUS.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// How events reach the other instances
+#[derive(Clone, Copy, PartialEq)]
+enum FanOut {
+    None,
+    Postgres,
+    Redis,
+}
+
+fn fan_out() -> FanOut {
+    *FAN_OUT.get_or_init(|| match std::env::var("EVENT_BUS").as_deref() {
+        Ok("postgres") => FanOut::Postgres,
+        Ok("redis") if redis_pool::client().is_some() => FanOut::Redis,
+        Ok("redis") => panic!("EVENT_BUS=redis needs REDIS_URL"),
+        Ok("memory") | Err(_) => FanOut::None,
+        Ok(other) => panic!("EVENT_BUS must be memory, postgres or redis, not {other}"),
+    })
+}
+
+fn instance() -> &'static str {
+    INSTANCE.get_or_init(|| nanoid!(12))
+}
+
+pub fn subscribe() -> broadcast::Receiver<NoteEvent> {
+    bus().subscribe()
+}
+
+fn deliver(event: NoteEvent) {
+    // only fails when nobody is listening
+    bus().send(event).ok();
+}
+
+/// Delivers the event here and, with fan-out on, to the other instances.
+/// Failing to reach them is logged rather than failing whatever the event is
+/// about.
+pub fn publish(connection: &mut PgConnection, event: NoteEvent) {
+    let payload = || {
+        json!(Notification {
+            origin: instance().to_owned(),
+            event: event.clone(),
+        })
+        .to_string()
+    };
+    let sent = match fan_out() {
+        FanOut::None => Ok(()),
+        FanOut::Postgres => sql_query("SELECT pg_notify($1, $2)")
+            .bind::<Text, _>(CHANNEL)
+            .bind::<Text, _>(payload())
+            .execute(connection)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        FanOut::Redis => redis_pool::pool()
+            .ok_or("redis is not set up".to_string())
+            .and_then(|pool| pool.get().map_err(|e| e.to_string()))
+            .and_then(|mut redis| {
+                redis::cmd("PUBLISH")
+                    .arg(CHANNEL)
+                    .arg(payload())
+                    .query::<i64>(&mut *redis)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }),
+    };
+    if let Err(e) = sent {
+        log::error!("Failed to send note event to other instances: {e}");
+    }
+    deliver(event);
+}
+
+/// Publishes an event about a note that's still around, along with how many
+/// reads it has left and when it expires
+pub fn note_changed(
+    connection: &mut PgConnection,
+    kind: Kind,
+    nid: &str,
+) -> Result<(), ServerError> {
+    let (reads_left, expires_at) = notes::table
+        .select((notes::delete_after_read, notes::expires_at))
+        .find(nid)
+        .first::<(Option<i32>, Option<SystemTime>)>(connection)?;
+    publish(
+        connection,
+        NoteEvent {
+            kind,
+            note_id: nid.to_owned(),
+            at: SystemTime::now(),
+            reads_left,
+            expires_at,
+        },
+    );
+    Ok(())
+}
+
+/// Publishes an event about a note that was just deleted
+pub fn note_gone(connection: &mut PgConnection, kind: Kind, nid: &str) {
+    publish(
+        connection,
+        NoteEvent {
+            kind,
+            note_id: nid.to_owned(),
+            at: SystemTime::now(),
+            reads_left: None,
+            expires_at: None,
+        },
+    );
+}
+
+fn receive(payload: &str) {
+    match serde_json::from_str::<Notification>(payload) {
+        Ok(notification) if notification.origin == instance() => (),
+        Ok(notification) => deliver(notification.event),
+        Err(e) => log::warn!("Ignoring malformed note event from another instance: {e}"),
+    }
+}
+
+/// Checks the certificate of the database as far as `sslmode` asks for
+#[derive(Debug)]
+struct DatabaseCertVerifier {
+    provider: Arc<CryptoProvider>,
+    /// What the certificate has to chain up to, nothing when it isn't checked
+    roots: Option<RootCertStore>,
+    check_name: bool,
+}
+
+impl ServerCertVerifier for DatabaseCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(roots) = &self.roots {
+            let cert = ParsedCertificate::try_from(end_entity)?;
+            rustls::client::verify_server_cert_signed_by_trust_anchor(
+                &cert,
+                roots,
+                intermediates,
+                now,
+                self.provider.signature_verification_algorithms.all,
+            )?;
+            if self.check_name {
+                rustls::client::verify_server_name(&cert, server_name)?;
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    // the handshake is always checked to be signed by the certificate it
+    // came with, checked or not
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// The certificates in `sslrootcert`, where `system` stands for the Mozilla
+/// roots
+fn root_certs(path: &str) -> RootCertStore {
+    if path == "system" {
+        return RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+    }
+
+    let mut roots = RootCertStore::empty();
+    for cert in
+        CertificateDer::pem_file_iter(path).expect("sslrootcert must be a readable PEM file")
+    {
+        roots
+            .add(cert.expect("sslrootcert must hold PEM certificates"))
+            .expect("sslrootcert must hold valid CA certificates");
+    }
+    roots
+}
+
+/// What the listener connects to the database with. `sslmode` and
+/// `sslrootcert` in `DATABASE_URL` mean what they mean to libpq:
+/// - `disable` never encrypts
+/// - `allow` and `prefer` (the default) encrypt when the database can, without
+///   checking its certificate
+/// - `require` always encrypts, and checks the certificate like `verify-ca`
+///   only when there's a root certificate
+/// - `verify-ca` checks that the certificate chains up to the root
+///   certificate, and `verify-full` that it names the host as well
+///
+/// The root certificate is `sslrootcert`, or `~/.postgresql/root.crt` when it
+/// exists.
+fn listener_config(database_url: &str) -> (postgres::Config, MakeRustlsConnect) {
+    let mut url = url::Url::parse(database_url).expect("DATABASE_URL must be a postgres:// URL");
+    let mut ssl_mode = "prefer".to_string();
+    let mut root_cert = None;
+    // neither is known to the postgres crate, which parses the rest
+    let rest = url
+        .query_pairs()
+        .filter_map(|(key, value)| match key.as_ref() {
+            "sslmode" => {
+                ssl_mode = value.into_owned();
+                None
+            }
+            "sslrootcert" => {
+                root_cert = Some(value.into_owned());
+                None
+            }
+            _ => Some((key.into_owned(), value.into_owned())),
+        })
+        .collect::<Vec<_>>();
+    url.set_query(None);
+    if !rest.is_empty() {
+        url.query_pairs_mut().extend_pairs(rest);
+    }
+    let mut config = url
+        .as_str()
+        .parse::<postgres::Config>()
+        .expect("DATABASE_URL must be a valid postgres URL");
+
+    let root_cert = root_cert.or_else(|| {
+        std::env::var("HOME")
+            .ok()
+            .map(|home| format!("{home}/.postgresql/root.crt"))
+            .filter(|path| Path::new(path).exists())
+    });
+    let (mode, verify, check_name) = match ssl_mode.as_str() {
+        "disable" => (SslMode::Disable, false, false),
+        "allow" | "prefer" => (SslMode::Prefer, false, false),
+        "require" => (SslMode::Require, root_cert.is_some(), false),
+        "verify-ca" => (SslMode::Require, true, false),
+        "verify-full" => (SslMode::Require, true, true),
+        other => panic!("sslmode in DATABASE_URL can't be {other}"),
+    };
+    config.ssl_mode(mode);
+    let roots = verify.then(|| {
+        root_certs(
+            root_cert
+                .as_deref()
+                .expect("sslmode=verify-ca and verify-full need sslrootcert in DATABASE_URL"),
+        )
+    });
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default TLS versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(DatabaseCertVerifier {
+            provider,
+            roots,
+            check_name,
+        }))
+        .with_no_client_auth();
+    (config, MakeRustlsConnect::new(tls))
+}
+
+/// Listens until the connection drops, calling `connected` once it's up
+fn listen_postgres(
+    config: &postgres::Config,
+    tls: &MakeRustlsConnect,
+    connected: impl FnOnce(),
+) -> Result<(), postgres::Error> {
+    let mut client = config.connect(tls.clone())?;
+    client.batch_execute(&format!("LISTEN {CHANNEL}"))?;
+    connected();
+
+    loop {
+        {
+            let mut notifications = client.notifications();
+            let mut pending = notifications.timeout_iter(LISTEN_PING_INTERVAL);
+            while let Some(notification) = pending.next()? {
+                receive(notification.payload());
+            }
+        }
+        // nothing came for a while, which is also what a dead connection
+        // looks like
+        client.batch_execute("SELECT 1")?;
+    }
+}
+
+/// Same as [`listen_postgres`], over Redis pub/sub
+fn listen_redis(client: &redis::Client, connected: impl FnOnce()) -> redis::RedisResult<()> {
+    let mut connection = client.get_connection()?;
+    let mut subscription = connection.as_pubsub();
+    subscription.subscribe(CHANNEL)?;
+    subscription.set_read_timeout(Some(LISTEN_PING_INTERVAL))?;
+    connected();
+
+    loop {
+        match subscription.get_message() {
+            Ok(message) => receive(&message.get_payload::<String>()?),
+            // subscribing again gets an answer from a live server, and
+            // nothing from a dead one
+            Err(e) if e.is_timeout() => subscription.subscribe(CHANNEL)?,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Starts the thread passing on events from other instances, when fan-out is
+/// on. Reconnects with a growing delay whenever the connection drops; events
+/// sent while it's down are missed.
+pub fn spawn_listener(database_url: String) {
+    let fan_out = fan_out();
+    if fan_out == FanOut::None {
+        return;
+    }
+
+    // made up front, so a bad sslmode or sslrootcert stops the server from
+    // starting
+    let (config, tls) = listener_config(&database_url);
+
+    std::thread::spawn(move || {
+        let mut delay = MIN_RECONNECT_DELAY;
+        loop {
+            let connected = || {
+                log::info!("Listening for note events from other instances");
+                delay = MIN_RECONNECT_DELAY;
+            };
+            let result = match (fan_out, redis_pool::client()) {
+                (FanOut::Redis, Some(client)) => {
+                    listen_redis(client, connected).map_err(|e| e.to_string())
+                }
+                _ => listen_postgres(&config, &tls, connected).map_err(|e| e.to_string()),
+            };
+            if let Err(e) = result {
+                log::error!("Lost the note event listener, reconnecting in {delay:?}: {e}");
+            }
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_config_follows_libpq_ssl_modes() {
+        for (url, mode) in [
+            ("postgres://u@db/himitsu", SslMode::Prefer),
+            ("postgres://u@db/himitsu?sslmode=disable", SslMode::Disable),
+            ("postgres://u@db/himitsu?sslmode=allow", SslMode::Prefer),
+            ("postgres://u@db/himitsu?sslmode=require", SslMode::Require),
+        ] {
+            assert_eq!(listener_config(url).0.get_ssl_mode(), mode, "{url}");
+        }
+    }
+
+    #[test]
+    fn listener_config_keeps_the_other_options() {
+        let (config, _) = listener_config(
+            "postgres://u:p%40ss@db:6543/himitsu?sslmode=require&application_name=listener&sslrootcert=system",
+        );
+        assert_eq!(config.get_ssl_mode(), SslMode::Require);
+        assert_eq!(config.get_application_name(), Some("listener"));
+        assert_eq!(config.get_password(), Some(&b"p@ss"[..]));
+        assert_eq!(config.get_ports(), &[6543]);
+    }
+
+    #[test]
+    #[should_panic(expected = "need sslrootcert")]
+    fn listener_config_refuses_to_verify_without_a_root() {
+        listener_config("postgres://u@db/himitsu?sslmode=verify-full");
+    }
+}
@@ -1,8 +1,11 @@
-use actix_web::web;
+use actix_web::{guard, web};
 use diesel::{pg::PgConnection, r2d2::ConnectionManager};
 
+pub mod dropbox;
 pub mod note;
+pub mod recipient;
 pub mod token;
+pub mod upload;
 pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -10,14 +13,88 @@ pub fn config(cfg: &mut web::ServiceConfig) {
         web::scope("/notes")
             .service(
                 web::resource("")
-                    .route(web::get().to(note::query::search_by_title))
+                    .route(web::get().to(note::search::search))
+                    .route(
+                        web::post()
+                            .guard(guard::fn_guard(|ctx| {
+                                ctx.head()
+                                    .headers()
+                                    .get("content-type")
+                                    .and_then(|v| v.to_str().ok())
+                                    .is_some_and(|v| v.starts_with("multipart/form-data"))
+                            }))
+                            .to(note::attachment::new),
+                    )
                     .route(web::post().to(note::mutate::new)),
             )
             .service(
                 web::resource("/{note_id}")
                     .route(web::get().to(note::query::info))
                     .route(web::post().to(note::query::decrypt_note))
+                    .route(web::put().to(note::mutate::edit))
                     .route(web::delete().to(note::mutate::del)),
+            )
+            .service(
+                web::resource("/{note_id}/raw")
+                    .route(web::get().to(note::query::raw))
+                    .route(web::post().to(note::query::raw)),
+            )
+            .service(web::resource("/{note_id}/html").route(web::get().to(note::render::html)))
+            .service(
+                web::resource("/{note_id}/attachments/{attachment_id}")
+                    .route(web::get().to(note::attachment::download))
+                    .route(web::post().to(note::attachment::download)),
+            )
+            .service(
+                web::resource("/{note_id}/slots")
+                    .route(web::get().to(note::slots::list))
+                    .route(web::post().to(note::slots::add)),
+            )
+            .service(
+                web::resource("/{note_id}/slots/{slot_id}")
+                    .route(web::delete().to(note::slots::revoke)),
+            )
+            .service(web::resource("/{note_id}/stats").route(web::get().to(note::stats::get)))
+            .service(
+                web::resource("/{note_id}/versions").route(web::get().to(note::versions::list)),
+            )
+            .service(
+                web::resource("/{note_id}/versions/{version_id}")
+                    .route(web::post().to(note::versions::restore)),
+            ),
+    )
+    .service(web::resource("/events").route(web::get().to(note::live::subscribe)))
+    .service(web::resource("/feed").route(web::get().to(note::feed::list)))
+    .service(web::resource("/titles").route(web::get().to(note::search::suggest)))
+    .service(web::resource("/tags").route(web::get().to(note::search::tag_counts)))
+    .service(
+        web::scope("/dropboxes")
+            .service(web::resource("").route(web::post().to(dropbox::new)))
+            .service(
+                web::resource("/{dropbox_id}")
+                    .route(web::get().to(dropbox::info))
+                    .route(web::put().to(dropbox::submit))
+                    .route(web::delete().to(dropbox::del)),
+            )
+            .service(web::resource("/{dropbox_id}/open").route(web::post().to(dropbox::open))),
+    )
+    .service(
+        web::scope("/recipients")
+            .service(web::resource("").route(web::post().to(recipient::register)))
+            .service(web::resource("/{handle}").route(web::get().to(recipient::find))),
+    )
+    .service(
+        web::scope("/uploads")
+            .service(
+                web::resource("")
+                    .route(web::method(actix_web::http::Method::OPTIONS).to(upload::options))
+                    .route(web::post().to(upload::new)),
+            )
+            .service(
+                web::resource("/{upload_id}")
+                    .route(web::head().to(upload::offset))
+                    .route(web::patch().to(upload::append))
+                    .route(web::delete().to(upload::del)),
             ),
     )
     .service(
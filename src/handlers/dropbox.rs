@@ -0,0 +1,377 @@
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use age::secrecy::ExposeSecret;
+use diesel::prelude::*;
+use nanoid::nanoid;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use tindercrypt::cryptors::RingCryptor;
+
+use super::{
+    note::{Claims, JWTAuth, JWTAuthQuery, Validator},
+    recipient, Pool,
+};
+use crate::{errors::ServerError, schema::dropboxes::dsl::*};
+
+#[derive(Clone, Debug, Queryable, Serialize)]
+struct DropboxInfo {
+    id: String,
+    recipient_handle: Option<String>,
+    public_key: String,
+    submitted_at: Option<SystemTime>,
+    created_at: SystemTime,
+    expires_at: Option<SystemTime>,
+    delete_after_read: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct NewDropbox {
+    recipient: Option<String>,
+    passphrase: Option<String>,
+    lifetime_in_secs: Option<u64>,
+    delete_after_read: Option<i32>,
+}
+
+pub async fn new(
+    req: HttpRequest,
+    input: web::Json<NewDropbox>,
+    auth: web::Query<JWTAuthQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut ids = match auth.unwrap() {
+        Some(token) => match token.decode() {
+            Ok(jwt) => jwt.claims.ids,
+            Err(_) => return Ok(HttpResponse::Forbidden().body("Your token is not valid")),
+        },
+        None => vec![],
+    };
+
+    let time_now = SystemTime::now();
+    let expiry_time = match input.lifetime_in_secs {
+        Some(duration) if duration > u32::MAX as u64 => {
+            return Ok(HttpResponse::BadRequest().body(
+                "current amount of time is not supported, please put in lower length of time!",
+            ));
+        }
+        Some(duration) if duration <= 30 => {
+            return Ok(HttpResponse::BadRequest().body("time input is too short"));
+        }
+        Some(duration) => Some(time_now + Duration::from_secs(duration)),
+        None => None,
+    };
+
+    if input.delete_after_read.is_some_and(|reads| reads < 1) {
+        return Ok(HttpResponse::BadRequest().body("delete_after_read must be at least 1"));
+    }
+
+    let mut connection = pool.get()?;
+
+    // a drop box either seals to a registered recipient, or to an identity
+    // made for it here whose secret half only its passphrase can unwrap
+    let (key, identity) = match (&input.recipient, &input.passphrase) {
+        (Some(handle), None) => {
+            match recipient::public_keys(&mut connection, std::slice::from_ref(handle))?.pop() {
+                Some((_, key)) => (key, None),
+                None => {
+                    return Ok(
+                        HttpResponse::BadRequest().body(format!("unknown recipient: {handle}"))
+                    );
+                }
+            }
+        }
+        (None, Some(passphrase)) => {
+            if !passphrase.is_valid_passphrase() {
+                return Ok(HttpResponse::BadRequest().body("passphrase is invalid"));
+            }
+
+            let generated = age::x25519::Identity::generate();
+            let wrapped = RingCryptor::new().seal_with_passphrase(
+                passphrase.as_bytes(),
+                generated.to_string().expose_secret().as_bytes(),
+            )?;
+            (generated.to_public().to_string(), Some(wrapped))
+        }
+        _ => {
+            return Ok(HttpResponse::BadRequest()
+                .body("a drop box needs either a recipient or a passphrase"));
+        }
+    };
+
+    let dropbox = diesel::insert_into(dropboxes)
+        .values((
+            id.eq(nanoid!()),
+            recipient_handle.eq(input.recipient.to_owned()),
+            public_key.eq(key),
+            wrapped_identity.eq(identity),
+            created_at.eq(time_now),
+            expires_at.eq(expiry_time),
+            delete_after_read.eq(input.delete_after_read),
+        ))
+        .returning((
+            id,
+            recipient_handle,
+            public_key,
+            submitted_at,
+            created_at,
+            expires_at,
+            delete_after_read,
+        ))
+        .get_result::<DropboxInfo>(&mut connection)?;
+
+    ids.push((dropbox.id.to_owned(), dropbox.created_at));
+    let token = JWTAuth::new(Claims {
+        ids,
+        iat: SystemTime::now(),
+        sub: req
+            .connection_info()
+            .peer_addr()
+            .unwrap_or("unknown")
+            .to_string(),
+    });
+    if token.is_err() {
+        diesel::delete(dropboxes.find(&dropbox.id)).execute(&mut connection)?;
+    }
+
+    Ok(HttpResponse::Created().json(json!({
+        "id": dropbox.id,
+        "recipient": dropbox.recipient_handle,
+        "public_key": dropbox.public_key,
+        "created_at": dropbox.created_at,
+        "expires_at": dropbox.expires_at,
+        "delete_after_read": dropbox.delete_after_read,
+        "token": token?,
+    })))
+}
+
+fn is_expired(dropbox_expiry: Option<SystemTime>) -> bool {
+    dropbox_expiry.is_some_and(|time| time <= SystemTime::now())
+}
+
+pub async fn info(
+    dropbox_id: web::Path<String>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    match dropboxes
+        .select((
+            id,
+            recipient_handle,
+            public_key,
+            submitted_at,
+            created_at,
+            expires_at,
+            delete_after_read,
+        ))
+        .find(dropbox_id.as_str())
+        .get_result::<DropboxInfo>(&mut connection)
+    {
+        Ok(dropbox) if !is_expired(dropbox.expires_at) => {
+            Ok(HttpResponse::Ok().json(json!(dropbox)))
+        }
+        Ok(_) | Err(diesel::result::Error::NotFound) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Submission {
+    content: String,
+}
+
+pub async fn submit(
+    dropbox_id: web::Path<String>,
+    input: web::Json<Submission>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    let (key, expiry) = match dropboxes
+        .select((public_key, expires_at))
+        .find(dropbox_id.as_str())
+        .get_result::<(String, Option<SystemTime>)>(&mut connection)
+    {
+        Ok(dropbox) if !is_expired(dropbox.1) => dropbox,
+        Ok(_) | Err(diesel::result::Error::NotFound) => {
+            return Ok(HttpResponse::NotFound().finish());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if input.content.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("content body is too small"));
+    }
+
+    let sealed = recipient::seal(&[key], input.content.as_bytes())?;
+
+    // only the first submission lands, the drop box is closed after it
+    let updated = diesel::update(
+        dropboxes
+            .filter(id.eq(dropbox_id.as_str()))
+            .filter(submitted_at.is_null()),
+    )
+    .set((content.eq(sealed), submitted_at.eq(SystemTime::now())))
+    .execute(&mut connection)?;
+
+    if updated == 0 {
+        return Ok(HttpResponse::Conflict().body("drop box has already received a note"));
+    }
+
+    Ok(HttpResponse::Created().json(json!({
+        "id": dropbox_id.as_str(),
+        "expires_at": expiry,
+    })))
+}
+
+#[derive(Queryable)]
+struct SubmittedNote {
+    wrapped_identity: Option<Vec<u8>>,
+    content: Option<Vec<u8>>,
+    submitted_at: Option<SystemTime>,
+    created_at: SystemTime,
+    expires_at: Option<SystemTime>,
+    delete_after_read: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct PassphraseField {
+    passphrase: Option<String>,
+}
+
+pub async fn open(
+    dropbox_id: web::Path<String>,
+    auth: web::Query<JWTAuthQuery>,
+    input: web::Json<PassphraseField>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    let auth = match auth.unwrap() {
+        Some(auth) => auth,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let dropbox = match dropboxes
+        .select((
+            wrapped_identity,
+            content,
+            submitted_at,
+            created_at,
+            expires_at,
+            delete_after_read,
+        ))
+        .find(dropbox_id.as_str())
+        .get_result::<SubmittedNote>(&mut connection)
+    {
+        Ok(dropbox) => dropbox,
+        Err(diesel::result::Error::NotFound) => return Ok(HttpResponse::NotFound().finish()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !auth.decode()?.claims.owns(&dropbox_id, dropbox.created_at) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    if is_expired(dropbox.expires_at) || dropbox.delete_after_read == Some(0) {
+        diesel::delete(dropboxes.find(dropbox_id.as_str())).execute(&mut connection)?;
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let sealed = match dropbox.content {
+        Some(sealed) => sealed,
+        None => return Ok(HttpResponse::NoContent().finish()),
+    };
+
+    let sealed_to_recipient = dropbox.wrapped_identity.is_none();
+    let dropbox_content = match dropbox.wrapped_identity {
+        Some(wrapped) => {
+            let passphrase = match &input.passphrase {
+                Some(passphrase) => passphrase,
+                None => return Ok(HttpResponse::Unauthorized().body("wrong passphrase")),
+            };
+            let unwrapped = match RingCryptor::new().open(passphrase.as_bytes(), &wrapped) {
+                Ok(unwrapped) => String::from_utf8(unwrapped)?,
+                Err(tindercrypt::errors::Error::DecryptionError)
+                | Err(tindercrypt::errors::Error::PassphraseTooSmall) => {
+                    return Ok(HttpResponse::Unauthorized().body("wrong passphrase"));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let identity =
+                age::x25519::Identity::from_str(&unwrapped).map_err(|_| ServerError::AgeError)?;
+
+            String::from_utf8(recipient::open(&identity, &sealed)?)?
+        }
+        // sealed to a recipient's own key, which never reaches the server
+        None => String::from_utf8(sealed)?,
+    };
+
+    // taken in one statement, so two opens racing for the last read can't
+    // both get it
+    let reads_left = match dropbox.delete_after_read {
+        Some(_) => match diesel::update(
+            dropboxes
+                .find(dropbox_id.as_str())
+                .filter(delete_after_read.gt(0)),
+        )
+        .set(delete_after_read.eq(delete_after_read - 1))
+        .returning(delete_after_read)
+        .get_result::<Option<i32>>(&mut connection)
+        .optional()?
+        {
+            Some(reads_left) => reads_left,
+            None => return Ok(HttpResponse::NotFound().finish()),
+        },
+        None => None,
+    };
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": dropbox_id.as_str(),
+        "content": dropbox_content,
+        "sealed": sealed_to_recipient,
+        "submitted_at": dropbox.submitted_at,
+        "created_at": dropbox.created_at,
+        "expires_at": dropbox.expires_at,
+        "request_left": reads_left,
+    })))
+}
+
+pub async fn del(
+    dropbox_id: web::Path<String>,
+    auth: web::Query<JWTAuthQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    let auth = match auth.unwrap() {
+        Some(auth) => auth,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    let creation_time = match dropboxes
+        .select(created_at)
+        .find(dropbox_id.as_str())
+        .get_result::<SystemTime>(&mut connection)
+    {
+        Ok(time) => time,
+        Err(diesel::result::Error::NotFound) => return Ok(HttpResponse::NotFound().finish()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut jwt = auth.decode()?;
+    if !jwt.claims.owns(&dropbox_id, creation_time) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    diesel::delete(dropboxes.find(dropbox_id.as_str())).execute(&mut connection)?;
+    jwt.claims.ids.retain(|t| t.0 != *dropbox_id);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": dropbox_id.as_str(),
+        "token": JWTAuth::new(jwt.claims)?,
+    })))
+}
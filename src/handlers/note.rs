@@ -14,18 +14,23 @@ pub struct Claims {
     pub sub: String,
 }
 
+impl Claims {
+    /// Whether this token was handed out for the note created at `note_created_at`
+    pub fn owns(&self, note_id: &str, note_created_at: SystemTime) -> bool {
+        self.ids
+            .iter()
+            .any(|(nid, created)| nid == note_id && *created == note_created_at)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct JWTAuthQuery {
     token: Option<String>,
 }
 
 impl JWTAuthQuery {
-    fn unwrap(&self) -> Option<JWTAuth> {
-        if let Some(token) = self.token.to_owned() {
-            Some(JWTAuth { token })
-        } else {
-            None
-        }
+    pub fn unwrap(&self) -> Option<JWTAuth> {
+        self.token.to_owned().map(|token| JWTAuth { token })
     }
 }
 
@@ -35,6 +40,7 @@ pub struct JWTAuth {
 }
 
 impl JWTAuth {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(claims: Claims) -> Result<String, jsonwebtoken::errors::Error> {
         let header = Header::new(Algorithm::HS512);
         let secret = std::env::var("SECRET_KEY").unwrap();
@@ -64,25 +70,36 @@ struct NoteInfo {
     expires_at: Option<SystemTime>,
     delete_after_read: Option<i32>,
     allow_delete_with_passphrase: bool,
+    recipient_encryption: bool,
+    content_type: String,
+    language: Option<String>,
 }
 
-trait Validator {
+pub trait Validator {
     fn is_valid_passphrase(&self) -> bool;
 }
 
 impl Validator for String {
     fn is_valid_passphrase(&self) -> bool {
-        if &self.len() < &4 {
-            return false;
-        } else if &self.len() >= &1024 {
-            return false;
-        }
-
-        true
+        self.len() >= 4 && self.len() < 1024
     }
 }
 
+pub mod attachment;
+pub mod compression;
+pub mod content_types;
+pub mod envelope;
+pub mod feed;
+pub mod highlight;
+pub mod live;
 pub mod mutate;
 pub mod query;
+pub mod render;
+pub mod search;
+pub mod slots;
+pub mod stats;
+pub mod stream;
+pub mod tags;
+pub mod versions;
 
 // pub async fn socket()
@@ -0,0 +1,298 @@
+use actix_multipart::Multipart;
+use actix_web::{
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web, HttpRequest, HttpResponse,
+};
+use diesel::{pg::PgConnection, prelude::*};
+use futures_util::TryStreamExt;
+use serde_derive::Serialize;
+use std::{io::Read, time::SystemTime};
+
+use super::{
+    super::recipient,
+    mutate,
+    query::{self, PassphraseField},
+    slots, stream, JWTAuthQuery, Pool,
+};
+
+use crate::{blob, errors::ServerError, schema::note_attachments, AppState};
+
+// the note itself is sent as JSON in a field of the form
+const MAX_NOTE_FIELD_SIZE: usize = 1024 * 1024;
+
+pub struct NewAttachment {
+    pub name: String,
+    pub mime_type: String,
+    pub content: NewContent,
+}
+
+/// What a new attachment holds
+pub enum NewContent {
+    Bytes(Vec<u8>),
+    /// Read as it's sealed, for files too big to hold in memory, along with
+    /// its size
+    Reader(Box<dyn Read + Send>, usize),
+}
+
+impl NewContent {
+    pub fn size(&self) -> usize {
+        match self {
+            NewContent::Bytes(bytes) => bytes.len(),
+            NewContent::Reader(_, size) => *size,
+        }
+    }
+}
+
+/// Seals an attachment the way its note is sealed, under the content key of
+/// the note or to its recipients, and offloads it when it's big. Returns what
+/// goes in the `content` and `content_blob` columns.
+pub fn seal(
+    content: NewContent,
+    key: Option<&[u8]>,
+    recipient_keys: &[String],
+) -> Result<(Vec<u8>, Option<String>), ServerError> {
+    let (reader, size) = match content {
+        NewContent::Bytes(bytes) => {
+            return blob::offload(if let Some(key) = key {
+                stream::seal(key, &bytes)?
+            } else if !recipient_keys.is_empty() {
+                recipient::seal(recipient_keys, &bytes)?
+            } else {
+                bytes
+            });
+        }
+        NewContent::Reader(reader, size) => (reader, size),
+    };
+
+    if let Some(key) = key {
+        blob::offload_reader(stream::Sealer::new(key, reader)?, stream::sealed_size(size))
+    } else if !recipient_keys.is_empty() {
+        // age only writes, so it seals on a thread of its own into a pipe the
+        // store reads from
+        let (sealed, sealing) = std::io::pipe()?;
+        let recipient_keys = recipient_keys.to_vec();
+        let sealer =
+            std::thread::spawn(move || recipient::seal_to(&recipient_keys, reader, sealing));
+        let offloaded = blob::offload_reader(sealed, size);
+        // an age file cut short by a failure is never kept
+        sealer.join().map_err(|_| ServerError::AgeError)??;
+        offloaded
+    } else {
+        blob::offload_reader(reader, size)
+    }
+}
+
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct AttachmentInfo {
+    pub id: i32,
+    pub name: String,
+    pub mime_type: String,
+    pub size: i32,
+    pub created_at: SystemTime,
+}
+
+pub fn list(connection: &mut PgConnection, nid: &str) -> Result<Vec<AttachmentInfo>, ServerError> {
+    Ok(note_attachments::table
+        .select((
+            note_attachments::id,
+            note_attachments::name,
+            note_attachments::mime_type,
+            note_attachments::size,
+            note_attachments::created_at,
+        ))
+        .filter(note_attachments::note_id.eq(nid))
+        .order(note_attachments::id.asc())
+        .get_results::<AttachmentInfo>(connection)?)
+}
+
+/// `POST /notes` with a `multipart/form-data` body: the note as JSON in a
+/// `note` field, and every file to attach in a `file` field.
+pub async fn new(
+    req: HttpRequest,
+    mut payload: Multipart,
+    auth: web::Query<JWTAuthQuery>,
+    env: web::Data<AppState>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut note = None;
+    let mut files: Vec<NewAttachment> = vec![];
+
+    while let Some(mut field) = payload.try_next().await? {
+        let disposition = field.content_disposition();
+        let field_name = disposition.get_name().unwrap_or_default().to_owned();
+        let file_name = disposition.get_filename().map(|f| f.to_owned());
+
+        let limit = match field_name.as_str() {
+            "note" => MAX_NOTE_FIELD_SIZE,
+            "file" => {
+                if files.len() >= env.max_attachments {
+                    return Ok(HttpResponse::BadRequest().body(format!(
+                        "a note can have at most {} attachments",
+                        env.max_attachments
+                    )));
+                }
+                env.max_attachment_size
+            }
+            _ => return Ok(HttpResponse::BadRequest().body(format!("unknown field: {field_name}"))),
+        };
+
+        let mut bytes: Vec<u8> = vec![];
+        while let Some(chunk) = field.try_next().await? {
+            if bytes.len() + chunk.len() > limit {
+                return Ok(HttpResponse::PayloadTooLarge()
+                    .body(format!("{field_name} is bigger than {limit} bytes")));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        if field_name == "note" {
+            match serde_json::from_slice::<mutate::NewNote>(&bytes) {
+                Ok(parsed) => note = Some(parsed),
+                Err(e) => return Ok(HttpResponse::BadRequest().body(format!("note: {e}"))),
+            }
+        } else {
+            let name = match file_name.filter(|f| !f.trim().is_empty() && f.len() <= 255) {
+                Some(name) => name,
+                None => {
+                    return Ok(HttpResponse::BadRequest()
+                        .body("every file needs a name of at most 255 bytes"));
+                }
+            };
+
+            files.push(NewAttachment {
+                name,
+                mime_type: field.content_type().essence_str().to_owned(),
+                content: NewContent::Bytes(bytes),
+            });
+        }
+    }
+
+    match note {
+        Some(note) => mutate::create(req, note, files, auth.into_inner(), pool).await,
+        None => Ok(HttpResponse::BadRequest().body("note field is missing")),
+    }
+}
+
+/// An attachment opened for downloading
+struct OpenedAttachment {
+    name: String,
+    mime_type: String,
+    recipient_encryption: bool,
+    /// Nothing for the decoy, which has no attachments of its own
+    chunks: Option<stream::Chunks>,
+}
+
+/// Opens an attachment, taking a read of its note
+fn open(
+    connection: &mut PgConnection,
+    nid: &str,
+    attachment_id: i32,
+    passphrase: Option<&str>,
+) -> Result<Result<OpenedAttachment, query::Rejection>, ServerError> {
+    let read = connection.transaction::<_, ServerError, _>(|connection| {
+        let attachment = match note_attachments::table
+            .select((
+                note_attachments::name,
+                note_attachments::mime_type,
+                note_attachments::content,
+                note_attachments::content_blob,
+            ))
+            .filter(note_attachments::id.eq(attachment_id))
+            .filter(note_attachments::note_id.eq(nid))
+            .get_result::<(String, String, Vec<u8>, Option<String>)>(connection)
+            .optional()?
+        {
+            Some(attachment) => attachment,
+            None => return Ok(Err(query::Rejection::NotFound)),
+        };
+
+        Ok(query::read(connection, nid, passphrase)?
+            .map(|(note, opened)| (note, opened.duress, opened.key, attachment)))
+    })?;
+    let (note, duress, key, (name, mime_type, sealed, sealed_blob)) = match read {
+        Ok(read) => read,
+        Err(rejection) => return Ok(Err(rejection)),
+    };
+    let mut attachment = OpenedAttachment {
+        name,
+        mime_type,
+        recipient_encryption: note.recipient_encryption,
+        chunks: None,
+    };
+    if duress {
+        return Ok(Ok(attachment));
+    }
+
+    let sealed = blob::reader(sealed, sealed_blob)?;
+    attachment.chunks = Some(if note.backend_encryption {
+        // the key the read just unlocked, so the slots aren't tried again
+        match key
+            .map(|key| slots::open_attachment(&key, sealed))
+            .transpose()?
+        {
+            Some(Some(chunks)) => chunks,
+            _ => return Ok(Err(query::Rejection::WrongPassphrase)),
+        }
+    } else {
+        stream::read_in_chunks(sealed)
+    });
+
+    Ok(Ok(attachment))
+}
+
+/// Downloads an attachment, which counts as a read of the note just like
+/// opening it does; backend-encrypted notes need the passphrase in the body.
+pub async fn download(
+    path: web::Path<(String, i32)>,
+    input: Option<web::Json<PassphraseField>>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let (note_id, attachment_id) = path.into_inner();
+    let passphrase = input.and_then(|i| i.into_inner().passphrase);
+
+    // opened on the blocking thread pool, since opening may wait on the blob
+    // store
+    let opened = {
+        let pool = pool.get_ref().clone();
+        let note_id = note_id.clone();
+        web::block(move || {
+            let mut connection = pool.get()?;
+            open(
+                &mut connection,
+                &note_id,
+                attachment_id,
+                passphrase.as_deref(),
+            )
+        })
+        .await??
+    };
+    let mut attachment = match opened {
+        Ok(attachment) => attachment,
+        Err(rejection) => return Ok(rejection.response(&note_id)),
+    };
+    let chunks = match attachment.chunks {
+        Some(chunks) => chunks,
+        None => {
+            slots::destroy_after_duress(pool.get_ref().clone(), note_id.clone());
+            return Ok(query::Rejection::NotFound.response(&note_id));
+        }
+    };
+
+    // attachments of notes sealed to recipients are age files, like the content
+    if attachment.recipient_encryption {
+        attachment.name.push_str(".age");
+        attachment.mime_type = "application/octet-stream".to_string();
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(attachment.mime_type)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(attachment.name)],
+        })
+        // the type is whatever the uploader said, so it's never sniffed or
+        // shown inline
+        .insert_header(("X-Content-Type-Options", "nosniff"))
+        .insert_header(("Content-Security-Policy", "sandbox; default-src 'none'"))
+        .streaming(stream::body(chunks)))
+}
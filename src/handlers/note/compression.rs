@@ -0,0 +1,147 @@
+//! zstd compression of note content, applied before it's sealed.
+//!
+//! It's only done when a note asks for it with `compress: true`, since the
+//! size of compressed content sealed behind a passphrase says something about
+//! what's inside.
+//!
+//! How the content was stored is kept in `notes.content_format`, so notes
+//! from before compression read as they always did. Only content of at least
+//! `COMPRESSION_MIN_SIZE` bytes that actually shrinks is kept compressed, and
+//! reading it back stops at `MAX_DECOMPRESSED_SIZE` bytes.
+use std::{io, sync::OnceLock};
+
+use super::stream::{self, Chunks};
+use crate::errors::ServerError;
+
+pub const UNCOMPRESSED: i16 = 0;
+pub const ZSTD: i16 = 1;
+
+struct Settings {
+    min_size: usize,
+    max_decompressed_size: usize,
+    level: i32,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(|| Settings {
+        min_size: std::env::var("COMPRESSION_MIN_SIZE")
+            .unwrap_or("1024".to_string())
+            .parse::<usize>()
+            .expect("must be an unsigned number of bytes"),
+        max_decompressed_size: std::env::var("MAX_DECOMPRESSED_SIZE")
+            .unwrap_or("67108864".to_string())
+            .parse::<usize>()
+            .expect("must be an unsigned number of bytes"),
+        level: std::env::var("COMPRESSION_LEVEL")
+            .unwrap_or("3".to_string())
+            .parse::<i32>()
+            .expect("must be a zstd compression level"),
+    })
+}
+
+/// Compresses `content` when it is worth it, returning what to seal along
+/// with its format
+pub fn compress(content: &[u8]) -> Result<(Vec<u8>, i16), ServerError> {
+    let settings = settings();
+    // content that couldn't be read back whole isn't compressed to begin with
+    if content.len() < settings.min_size || content.len() > settings.max_decompressed_size {
+        return Ok((content.to_vec(), UNCOMPRESSED));
+    }
+
+    let compressed = zstd::encode_all(content, settings.level)?;
+    if compressed.len() < content.len() {
+        Ok((compressed, ZSTD))
+    } else {
+        Ok((content.to_vec(), UNCOMPRESSED))
+    }
+}
+
+/// Reverses [`compress`] on opened content as it's read
+pub fn decompress(chunks: Chunks, format: i16) -> Result<Chunks, ServerError> {
+    match format {
+        UNCOMPRESSED => Ok(chunks),
+        ZSTD => {
+            let cap = settings().max_decompressed_size;
+            let decoder = zstd::Decoder::new(stream::ChunksReader::new(chunks))?;
+
+            let mut total = 0;
+            Ok(Box::new(stream::read_in_chunks(decoder).map(
+                move |chunk| {
+                    let chunk = chunk?;
+                    total += chunk.len();
+                    if total > cap {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("content decompresses past {cap} bytes"),
+                        ));
+                    }
+                    Ok(chunk)
+                },
+            )))
+        }
+        _ => {
+            log::error!("unknown content format {format}");
+            Err(ServerError::Default)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn decompressed(content: Vec<u8>, format: i16) -> Result<Vec<u8>, ServerError> {
+        stream::concat(decompress(
+            stream::read_in_chunks(io::Cursor::new(content)),
+            format,
+        )?)
+    }
+
+    #[test]
+    fn formats_keep_their_stored_values() {
+        // written to notes.content_format, so these can never change
+        assert_eq!(UNCOMPRESSED, 0);
+        assert_eq!(ZSTD, 1);
+    }
+
+    #[test]
+    fn compress_round_trips() {
+        let content = "the same line over and over\n".repeat(10_000).into_bytes();
+        let (compressed, format) = compress(&content).unwrap();
+        assert_eq!(format, ZSTD);
+        assert!(compressed.len() < content.len());
+        assert_eq!(decompressed(compressed, format).unwrap(), content);
+    }
+
+    #[test]
+    fn compress_leaves_small_content_alone() {
+        let content = b"a".repeat(100);
+        assert_eq!(compress(&content).unwrap(), (content, UNCOMPRESSED));
+    }
+
+    #[test]
+    fn compress_leaves_content_that_does_not_shrink_alone() {
+        let mut content = vec![0u8; 4096];
+        tindercrypt::rand::fill_buf(&mut content);
+        assert_eq!(compress(&content).unwrap(), (content.clone(), UNCOMPRESSED));
+        assert_eq!(
+            decompressed(content.clone(), UNCOMPRESSED).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn decompress_rejects_unknown_formats() {
+        assert!(decompress(stream::read_in_chunks(io::empty()), 2).is_err());
+    }
+
+    #[test]
+    fn decompress_stops_at_the_cap() {
+        let cap = settings().max_decompressed_size;
+        let bomb = zstd::encode_all(io::repeat(0).take(cap as u64 + 1), 3).unwrap();
+        assert!(decompressed(bomb, ZSTD).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+//! What kind of text a note holds, so clients and the raw endpoint know how to
+//! present it.
+//!
+//! Only types a browser won't run when given the raw content are allowed,
+//! which is why there's no `text/html`, `image/svg+xml` or XML at all (an
+//! XHTML namespace makes any of it a page). Raw responses are sandboxed by
+//! their CSP on top of that.
+
+pub const DEFAULT: &str = "text/plain";
+
+pub const ALLOWED: &[&str] = &[
+    "text/plain",
+    "text/markdown",
+    "text/csv",
+    "application/json",
+    "application/yaml",
+    "application/toml",
+    "application/x-sh",
+];
+
+/// Names clients can give as the syntax of a note
+pub const LANGUAGES: &[&str] = &[
+    "bash",
+    "c",
+    "cpp",
+    "csharp",
+    "css",
+    "diff",
+    "dockerfile",
+    "go",
+    "html",
+    "ini",
+    "java",
+    "javascript",
+    "json",
+    "kotlin",
+    "lua",
+    "makefile",
+    "markdown",
+    "nginx",
+    "php",
+    "python",
+    "ruby",
+    "rust",
+    "sql",
+    "swift",
+    "toml",
+    "typescript",
+    "xml",
+    "yaml",
+];
+
+pub fn is_allowed(content_type: &str) -> bool {
+    ALLOWED.contains(&content_type)
+}
+
+pub fn is_known_language(language: &str) -> bool {
+    LANGUAGES.contains(&language)
+}
@@ -0,0 +1,287 @@
+//! Envelope for content that reaches the server already encrypted by a client.
+//!
+//! Clients send the envelope as the JSON string in `content` with
+//! `is_currently_encrypted` set. Binary fields are standard base64 with padding:
+//!
+//! ```json
+//! {
+//!   "v": 1,
+//!   "alg": "AES-256-GCM",
+//!   "kdf": { "name": "PBKDF2-SHA256", "iterations": 210000, "salt": "..." },
+//!   "nonce": "...",
+//!   "ct": "..."
+//! }
+//! ```
+//!
+//! `alg` is `AES-256-GCM` (12 byte nonce) or `XChaCha20-Poly1305` (24 byte
+//! nonce), and `ct` holds the ciphertext with its 16 byte tag appended.
+//! `kdf` is one of:
+//! - `{ "name": "PBKDF2-SHA256", "iterations", "salt" }`
+//! - `{ "name": "Argon2id", "memory_kib", "iterations", "parallelism", "salt" }`
+//! - `{ "name": "none" }` when the key wasn't derived from a passphrase
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+pub const CURRENT_VERSION: u8 = 1;
+
+const TAG_SIZE: usize = 16;
+const MIN_SALT_SIZE: usize = 16;
+const MIN_PBKDF2_ITERATIONS: u32 = 100_000;
+const MIN_ARGON2_MEMORY_KIB: u32 = 19_456;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Envelope {
+    pub v: u8,
+    pub alg: String,
+    pub kdf: Kdf,
+    pub nonce: String,
+    pub ct: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "name", deny_unknown_fields)]
+pub enum Kdf {
+    #[serde(rename = "PBKDF2-SHA256")]
+    Pbkdf2 { iterations: u32, salt: String },
+    #[serde(rename = "Argon2id")]
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+        salt: String,
+    },
+    #[serde(rename = "none")]
+    None,
+}
+
+fn nonce_size(alg: &str) -> Option<usize> {
+    match alg {
+        "AES-256-GCM" => Some(12),
+        "XChaCha20-Poly1305" => Some(24),
+        _ => None,
+    }
+}
+
+fn decoded_len(field: &str, value: &str) -> Result<usize, String> {
+    STANDARD
+        .decode(value)
+        .map(|bytes| bytes.len())
+        .map_err(|_| format!("envelope {field} is not valid base64"))
+}
+
+impl Envelope {
+    /// Parses and validates an envelope, with the reason it was rejected as
+    /// the error.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let envelope: Envelope = serde_json::from_str(raw)
+            .map_err(|e| format!("content is not a valid encryption envelope: {e}"))?;
+
+        if envelope.v != CURRENT_VERSION {
+            return Err(format!("envelope version {} is not supported", envelope.v));
+        }
+
+        let expected_nonce_size = nonce_size(&envelope.alg)
+            .ok_or_else(|| format!("envelope algorithm {} is not supported", envelope.alg))?;
+        if decoded_len("nonce", &envelope.nonce)? != expected_nonce_size {
+            return Err(format!(
+                "{} needs a {expected_nonce_size} byte nonce",
+                envelope.alg
+            ));
+        }
+
+        if decoded_len("ct", &envelope.ct)? <= TAG_SIZE {
+            return Err("envelope ciphertext is too short".to_string());
+        }
+
+        match &envelope.kdf {
+            Kdf::Pbkdf2 { iterations, salt } => {
+                if *iterations < MIN_PBKDF2_ITERATIONS {
+                    return Err(format!(
+                        "PBKDF2-SHA256 needs at least {MIN_PBKDF2_ITERATIONS} iterations"
+                    ));
+                }
+                if decoded_len("salt", salt)? < MIN_SALT_SIZE {
+                    return Err(format!("kdf salt must be at least {MIN_SALT_SIZE} bytes"));
+                }
+            }
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+                salt,
+            } => {
+                if *memory_kib < MIN_ARGON2_MEMORY_KIB || *iterations < 1 || *parallelism < 1 {
+                    return Err("Argon2id parameters are too weak".to_string());
+                }
+                if decoded_len("salt", salt)? < MIN_SALT_SIZE {
+                    return Err(format!("kdf salt must be at least {MIN_SALT_SIZE} bytes"));
+                }
+            }
+            Kdf::None => (),
+        }
+
+        Ok(envelope)
+    }
+
+    /// What `info` tells about the envelope; salt, nonce and ciphertext are
+    /// left out.
+    pub fn metadata(&self) -> serde_json::Value {
+        let kdf = match &self.kdf {
+            Kdf::Pbkdf2 { iterations, .. } => {
+                json!({ "name": "PBKDF2-SHA256", "iterations": iterations })
+            }
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+                ..
+            } => json!({
+                "name": "Argon2id",
+                "memory_kib": memory_kib,
+                "iterations": iterations,
+                "parallelism": parallelism,
+            }),
+            Kdf::None => json!({ "name": "none" }),
+        };
+
+        json!({
+            "v": self.v,
+            "alg": self.alg,
+            "kdf": kdf,
+            "ciphertext_size": STANDARD.decode(&self.ct).map_or(0, |ct| ct.len()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn b64(size: usize) -> String {
+        STANDARD.encode(vec![1u8; size])
+    }
+
+    fn pbkdf2() -> serde_json::Value {
+        json!({
+            "v": 1,
+            "alg": "AES-256-GCM",
+            "kdf": { "name": "PBKDF2-SHA256", "iterations": 210_000, "salt": b64(16) },
+            "nonce": b64(12),
+            "ct": b64(TAG_SIZE + 1),
+        })
+    }
+
+    fn argon2() -> serde_json::Value {
+        json!({
+            "v": 1,
+            "alg": "XChaCha20-Poly1305",
+            "kdf": {
+                "name": "Argon2id",
+                "memory_kib": 19_456,
+                "iterations": 2,
+                "parallelism": 1,
+                "salt": b64(16),
+            },
+            "nonce": b64(24),
+            "ct": b64(64),
+        })
+    }
+
+    fn parse(envelope: serde_json::Value) -> Result<Envelope, String> {
+        Envelope::parse(&envelope.to_string())
+    }
+
+    fn with(
+        mut envelope: serde_json::Value,
+        pointer: &str,
+        value: serde_json::Value,
+    ) -> serde_json::Value {
+        *envelope.pointer_mut(pointer).unwrap() = value;
+        envelope
+    }
+
+    #[test]
+    fn parse_accepts_valid_envelopes() {
+        assert!(parse(pbkdf2()).is_ok());
+        assert!(parse(argon2()).is_ok());
+        assert!(parse(with(pbkdf2(), "/kdf", json!({ "name": "none" }))).is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_what_is_not_an_envelope() {
+        assert!(Envelope::parse("plain text").is_err());
+        assert!(Envelope::parse("{}").is_err());
+
+        let mut extra = pbkdf2();
+        extra["key"] = json!(b64(32));
+        assert!(parse(extra).is_err());
+
+        let mut extra_kdf = pbkdf2();
+        extra_kdf["kdf"]["pepper"] = json!("x");
+        assert!(parse(extra_kdf).is_err());
+
+        assert!(parse(with(pbkdf2(), "/kdf/name", json!("scrypt"))).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_other_versions() {
+        assert!(parse(with(pbkdf2(), "/v", json!(2))).is_err());
+        assert!(parse(with(pbkdf2(), "/v", json!(0))).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_unknown_algorithms() {
+        assert!(parse(with(pbkdf2(), "/alg", json!("AES-128-CBC"))).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_nonces_of_the_wrong_size() {
+        assert!(parse(with(pbkdf2(), "/nonce", json!(b64(24)))).is_err());
+        assert!(parse(with(argon2(), "/nonce", json!(b64(12)))).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_ciphertext_no_longer_than_the_tag() {
+        assert!(parse(with(pbkdf2(), "/ct", json!(b64(TAG_SIZE)))).is_err());
+        assert!(parse(with(pbkdf2(), "/ct", json!(""))).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_weak_pbkdf2() {
+        let iterations = MIN_PBKDF2_ITERATIONS - 1;
+        assert!(parse(with(pbkdf2(), "/kdf/iterations", json!(iterations))).is_err());
+        assert!(parse(with(pbkdf2(), "/kdf/salt", json!(b64(MIN_SALT_SIZE - 1)))).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_weak_argon2() {
+        let memory_kib = MIN_ARGON2_MEMORY_KIB - 1;
+        assert!(parse(with(argon2(), "/kdf/memory_kib", json!(memory_kib))).is_err());
+        assert!(parse(with(argon2(), "/kdf/iterations", json!(0))).is_err());
+        assert!(parse(with(argon2(), "/kdf/parallelism", json!(0))).is_err());
+        assert!(parse(with(argon2(), "/kdf/salt", json!(b64(MIN_SALT_SIZE - 1)))).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_bad_base64() {
+        assert!(parse(with(pbkdf2(), "/nonce", json!("not base64!"))).is_err());
+        assert!(parse(with(pbkdf2(), "/ct", json!("AAAA-_"))).is_err());
+        assert!(parse(with(pbkdf2(), "/kdf/salt", json!("%%%%"))).is_err());
+    }
+
+    #[test]
+    fn metadata_leaves_out_secrets_and_bytes() {
+        let metadata = parse(pbkdf2()).unwrap().metadata();
+        assert_eq!(
+            metadata,
+            json!({
+                "v": 1,
+                "alg": "AES-256-GCM",
+                "kdf": { "name": "PBKDF2-SHA256", "iterations": 210_000 },
+                "ciphertext_size": TAG_SIZE + 1,
+            })
+        );
+    }
+}
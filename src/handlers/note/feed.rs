@@ -0,0 +1,247 @@
+//! Public listing of discoverable notes, a page at a time.
+//!
+//! Pages are found with keyset cursors rather than offsets: a cursor holds
+//! the sort key and id of the last note of a page, and the next page starts
+//! right after it. Notes expiring or being added in between then can't shift
+//! later pages.
+use actix_web::{web, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use diesel::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use super::{NoteInfo, Pool};
+
+use crate::{
+    errors::ServerError,
+    schema::{note_tags, notes::dsl::*},
+};
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    #[default]
+    Newest,
+    Oldest,
+    /// Notes without an expiry are left out
+    Expiring,
+    MostViewed,
+}
+
+#[derive(Deserialize)]
+pub struct FeedQuery {
+    pub sort: Option<Sort>,
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// Where a page ended. Times are kept as microseconds, which is as precise
+/// as Postgres keeps them.
+#[derive(Deserialize, Serialize)]
+struct Cursor {
+    sort: Sort,
+    key: i64,
+    id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(json!(self).to_string())
+    }
+
+    fn decode(raw: &str) -> Option<Self> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(raw).ok()?).ok()
+    }
+}
+
+fn to_micros(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_micros() as i64)
+        .unwrap_or_default()
+}
+
+fn from_micros(micros: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_micros(micros.max(0) as u64)
+}
+
+pub async fn list(
+    input: web::Query<FeedQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    let sort = input.sort.unwrap_or_default();
+    let limit = input.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let cursor = match input.cursor.as_deref().map(Cursor::decode) {
+        Some(Some(cursor)) if cursor.sort == sort => Some(cursor),
+        Some(_) => return Ok(HttpResponse::BadRequest().body("cursor is not valid for this sort")),
+        None => None,
+    };
+
+    let mut query = notes
+        .select((
+            (
+                id,
+                title,
+                backend_encryption,
+                frontend_encryption,
+                created_at,
+                expires_at,
+                delete_after_read,
+                allow_delete_with_passphrase,
+                recipient_encryption,
+                content_type,
+                language,
+            ),
+            view_count,
+        ))
+        .filter(discoverable.eq(true))
+        .filter(backend_encryption.eq(false))
+        .filter(frontend_encryption.eq(false))
+        .filter(recipient_encryption.eq(false))
+        // the cleanup task may not have gotten to these yet
+        .filter(expires_at.is_null().or(expires_at.gt(SystemTime::now())))
+        .filter(delete_after_read.is_null().or(delete_after_read.gt(0)))
+        .into_boxed();
+
+    query = match sort {
+        Sort::Newest => query.order((created_at.desc(), id.desc())),
+        Sort::Oldest => query.order((created_at.asc(), id.asc())),
+        Sort::Expiring => query
+            .filter(expires_at.is_not_null())
+            .order((expires_at.asc(), id.asc())),
+        Sort::MostViewed => query.order((view_count.desc(), id.desc())),
+    };
+
+    if let Some(cursor) = cursor {
+        query = match sort {
+            Sort::Newest => {
+                let time = from_micros(cursor.key);
+                query.filter(
+                    created_at
+                        .lt(time)
+                        .or(created_at.eq(time).and(id.lt(cursor.id))),
+                )
+            }
+            Sort::Oldest => {
+                let time = from_micros(cursor.key);
+                query.filter(
+                    created_at
+                        .gt(time)
+                        .or(created_at.eq(time).and(id.gt(cursor.id))),
+                )
+            }
+            Sort::Expiring => {
+                let time = from_micros(cursor.key);
+                query.filter(
+                    expires_at
+                        .gt(time)
+                        .or(expires_at.eq(time).and(id.gt(cursor.id))),
+                )
+            }
+            Sort::MostViewed => query.filter(
+                view_count
+                    .lt(cursor.key)
+                    .or(view_count.eq(cursor.key).and(id.lt(cursor.id))),
+            ),
+        };
+    }
+
+    // one more than asked for tells whether there's a page after this one
+    let mut page = query
+        .limit(limit + 1)
+        .load::<(NoteInfo, i64)>(&mut connection)?;
+    let next_cursor = if page.len() as i64 > limit {
+        page.truncate(limit as usize);
+        page.last().map(|(note, views)| {
+            Cursor {
+                sort,
+                key: match sort {
+                    Sort::Newest | Sort::Oldest => to_micros(note.created_at),
+                    Sort::Expiring => note.expires_at.map(to_micros).unwrap_or_default(),
+                    Sort::MostViewed => *views,
+                },
+                id: note.id.to_owned(),
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+
+    let mut tags_of = HashMap::<String, Vec<String>>::new();
+    for (nid, tag) in note_tags::table
+        .select((note_tags::note_id, note_tags::tag))
+        .filter(note_tags::note_id.eq_any(page.iter().map(|(note, _)| &note.id)))
+        .order(note_tags::tag.asc())
+        .get_results::<(String, String)>(&mut connection)?
+    {
+        tags_of.entry(nid).or_default().push(tag);
+    }
+
+    let page = page
+        .into_iter()
+        .map(|(note, views)| {
+            let mut item = json!(note);
+            item["tags"] = json!(tags_of.remove(&note.id).unwrap_or_default());
+            item["view_count"] = json!(views);
+            item
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "notes": page,
+        "next_cursor": next_cursor,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = Cursor {
+            sort: Sort::MostViewed,
+            key: 42,
+            id: "abc123".to_string(),
+        };
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.sort, Sort::MostViewed);
+        assert_eq!(decoded.key, 42);
+        assert_eq!(decoded.id, "abc123");
+    }
+
+    #[test]
+    fn cursor_is_url_safe() {
+        let cursor = Cursor {
+            sort: Sort::Newest,
+            key: i64::MAX,
+            id: "?&=/+".to_string(),
+        };
+        assert!(cursor
+            .encode()
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    }
+
+    #[test]
+    fn cursor_rejects_garbage() {
+        assert!(Cursor::decode("not a cursor").is_none());
+        assert!(Cursor::decode(&URL_SAFE_NO_PAD.encode("{\"sort\":\"newest\"}")).is_none());
+    }
+
+    #[test]
+    fn micros_round_trip() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_792_365_417_932_168);
+        assert_eq!(from_micros(to_micros(time)), time);
+        assert_eq!(from_micros(-5), SystemTime::UNIX_EPOCH);
+    }
+}
@@ -0,0 +1,89 @@
+//! Server-side syntax highlighting of code notes with syntect, styled inline
+//! so the page needs nothing but itself.
+use std::sync::OnceLock;
+
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Color, Theme, ThemeSet},
+    html::{styled_line_to_highlighted_html, IncludeBackground},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+pub const DEFAULT_THEME: &str = "InspiredGitHub";
+
+static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+
+fn syntaxes() -> &'static SyntaxSet {
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn themes() -> &'static ThemeSet {
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+pub fn theme_names() -> Vec<&'static str> {
+    themes().themes.keys().map(String::as_str).collect()
+}
+
+pub fn theme(name: &str) -> Option<&'static Theme> {
+    themes().themes.get(name)
+}
+
+/// Picks the syntax for a note from its language, or from its content type
+/// when it has none. Falls back to plain text.
+fn syntax(language: Option<&str>, content_type: &str) -> &'static SyntaxReference {
+    let token = match (language, content_type) {
+        // names from content_types::LANGUAGES syntect knows by another one
+        (Some("csharp"), _) => "cs",
+        (Some("makefile"), _) => "make",
+        (Some(language), _) => language,
+        (None, "application/json") => "json",
+        (None, "application/yaml") => "yaml",
+        (None, "application/x-sh") => "bash",
+        (None, "text/csv") => "csv",
+        (None, "text/markdown") => "markdown",
+        (None, _) => "txt",
+    };
+
+    syntaxes()
+        .find_syntax_by_token(token)
+        .unwrap_or_else(|| syntaxes().find_syntax_plain_text())
+}
+
+/// Highlights `code` into a `<pre>` block, optionally with line numbers
+pub fn render(
+    code: &str,
+    language: Option<&str>,
+    content_type: &str,
+    theme: &Theme,
+    line_numbers: bool,
+) -> Result<String, syntect::Error> {
+    let mut highlighter = HighlightLines::new(syntax(language, content_type), theme);
+
+    let foreground = theme.settings.foreground.unwrap_or(Color::BLACK);
+    let background = theme.settings.background.unwrap_or(Color::WHITE);
+    let mut html = format!(
+        "<pre style=\"background-color:#{:02x}{:02x}{:02x};color:#{:02x}{:02x}{:02x};padding:1em;\">\n",
+        background.r, background.g, background.b, foreground.r, foreground.g, foreground.b,
+    );
+
+    let width = code.lines().count().max(1).to_string().len();
+    for (index, line) in LinesWithEndings::from(code).enumerate() {
+        if line_numbers {
+            html.push_str(&format!(
+                "<span style=\"opacity:0.5;user-select:none;\">{:>width$}  </span>",
+                index + 1,
+            ));
+        }
+        let regions = highlighter.highlight_line(line, syntaxes())?;
+        html.push_str(&styled_line_to_highlighted_html(
+            &regions,
+            IncludeBackground::No,
+        )?);
+    }
+    html.push_str("</pre>\n");
+
+    Ok(html)
+}
@@ -0,0 +1,107 @@
+//! Server-Sent Events for the owner of notes, so a page showing them doesn't
+//! have to poll for reads left and expiry.
+//!
+//! Subscribing takes the token handed out when the notes were created, and
+//! follows every note of it that's still around:
+//!
+//! ```text
+//! event: read
+//! data: {"type":"read","note_id":"abc123","at":...,"reads_left":2,"expires_at":...}
+//! ```
+//!
+//! A subscriber too slow to keep up gets a `lagged` event telling how many it
+//! missed, after which it's best to fetch the notes again.
+use actix_web::{web, HttpResponse};
+use diesel::prelude::*;
+use serde_json::json;
+use std::{collections::HashSet, convert::Infallible, time::Duration};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use super::{JWTAuthQuery, Pool};
+
+use crate::{
+    errors::ServerError,
+    events::{self, Kind, NoteEvent},
+    schema::notes,
+};
+
+// proxies tend to close connections that stay quiet for a minute
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+fn message(event: &str, data: serde_json::Value) -> web::Bytes {
+    web::Bytes::from(format!("event: {event}\ndata: {data}\n\n"))
+}
+
+struct Subscription {
+    receiver: Receiver<NoteEvent>,
+    following: HashSet<String>,
+}
+
+impl Subscription {
+    /// Waits for the next thing to send, or `None` once every note followed
+    /// is gone
+    async fn next(&mut self) -> Option<web::Bytes> {
+        while !self.following.is_empty() {
+            match tokio::time::timeout(KEEP_ALIVE, self.receiver.recv()).await {
+                Err(_) => return Some(web::Bytes::from_static(b": keep-alive\n\n")),
+                Ok(Ok(event)) if self.following.contains(&event.note_id) => {
+                    if event.kind == Kind::Deleted || event.kind == Kind::Expired {
+                        self.following.remove(&event.note_id);
+                    }
+                    return Some(message(event.kind.as_str(), json!(event)));
+                }
+                Ok(Ok(_)) => continue,
+                Ok(Err(RecvError::Lagged(missed))) => {
+                    return Some(message("lagged", json!({ "missed": missed })));
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+            }
+        }
+        None
+    }
+}
+
+pub async fn subscribe(
+    auth: web::Query<JWTAuthQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    let claims = match auth.unwrap() {
+        Some(auth) => auth.decode()?.claims,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+
+    // subscribed before looking the notes up, so nothing happening in
+    // between is missed
+    let receiver = events::subscribe();
+    let following = notes::table
+        .select((notes::id, notes::created_at))
+        .filter(notes::id.eq_any(claims.ids.iter().map(|(nid, _)| nid)))
+        .get_results::<(String, std::time::SystemTime)>(&mut connection)?
+        .into_iter()
+        .filter(|(nid, created)| claims.owns(nid, *created))
+        .map(|(nid, _)| nid)
+        .collect::<HashSet<_>>();
+    if following.is_empty() {
+        return Ok(HttpResponse::NotFound().body("token holds no notes to follow"));
+    }
+
+    let stream = futures_util::stream::unfold(
+        Subscription {
+            receiver,
+            following,
+        },
+        |mut subscription| async move {
+            let bytes = subscription.next().await?;
+            Some((Ok::<_, Infallible>(bytes), subscription))
+        },
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // keeps nginx from buffering the events
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(stream))
+}
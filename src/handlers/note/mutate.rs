@@ -4,23 +4,49 @@ use nanoid::nanoid;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 use std::time::{Duration, SystemTime};
-use tindercrypt::cryptors::RingCryptor;
 
-use super::{Claims, JWTAuth, JWTAuthQuery, NoteInfo, Pool};
+use super::{
+    super::recipient,
+    attachment::{self, NewAttachment},
+    compression, content_types,
+    envelope::Envelope,
+    search, slots, stream, tags, versions, Claims, JWTAuth, JWTAuthQuery, NoteInfo, Pool,
+};
 
-use crate::{errors::ServerError, schema::notes::dsl::*};
+use crate::{
+    blob,
+    errors::ServerError,
+    events::{self, Kind},
+    mailer,
+    schema::{note_attachments, note_key_slots, note_recipients, note_tags, notes::dsl::*},
+    webhook,
+};
+
+const MAX_RECIPIENTS: usize = 16;
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct NewNote {
     id: Option<String>,
     title: Option<String>,
-    content: String,
+    #[serde(default)]
+    pub content: String,
     discoverable: Option<bool>,
-    passphrase: Option<String>,
+    pub passphrase: Option<String>,
     is_currently_encrypted: Option<bool>,
     lifetime_in_secs: Option<u64>,
     delete_after_read: Option<i32>,
     allow_delete_with_passphrase: Option<bool>,
+    pub duress_passphrase: Option<String>,
+    decoy_content: Option<String>,
+    destroy_on_duress: Option<bool>,
+    recipients: Option<Vec<String>>,
+    compress: Option<bool>,
+    content_type: Option<String>,
+    language: Option<String>,
+    tags: Option<Vec<String>>,
+    webhook_url: Option<String>,
+    webhook_secret: Option<String>,
+    notify_email: Option<String>,
 }
 
 pub async fn new(
@@ -28,6 +54,17 @@ pub async fn new(
     input: web::Json<NewNote>,
     auth: web::Query<JWTAuthQuery>,
     pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    create(req, input.into_inner(), vec![], auth.into_inner(), pool).await
+}
+
+/// Creates a note along with its attachments, sealing both the same way
+pub async fn create(
+    req: HttpRequest,
+    input: NewNote,
+    files: Vec<NewAttachment>,
+    auth: JWTAuthQuery,
+    pool: web::Data<Pool>,
 ) -> Result<HttpResponse, ServerError> {
     let unwraped_token: Option<jsonwebtoken::TokenData<Claims>>;
     if let Some(token) = &auth.unwrap() {
@@ -87,42 +124,227 @@ pub async fn new(
         }
     }
 
+    let note_content_type = input
+        .content_type
+        .to_owned()
+        .unwrap_or(content_types::DEFAULT.to_string());
+    if !content_types::is_allowed(&note_content_type) {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "content_type must be one of {}",
+            content_types::ALLOWED.join(", ")
+        )));
+    }
+    if let Some(l) = &input.language {
+        if !content_types::is_known_language(l) {
+            return Ok(HttpResponse::BadRequest().body(format!("unknown language: {l}")));
+        }
+    }
+
+    let note_tags_list = match tags::normalize(input.tags.as_deref().unwrap_or_default()) {
+        Ok(t) => t,
+        Err(reason) => return Ok(HttpResponse::BadRequest().body(reason)),
+    };
+
+    let webhook_hook = match (&input.webhook_url, &input.webhook_secret) {
+        (Some(url), secret) => {
+            if let Err(reason) = webhook::validate_url(url) {
+                return Ok(HttpResponse::BadRequest().body(reason));
+            }
+            let secret = match secret {
+                Some(secret) => match webhook::validate_secret(secret) {
+                    Ok(()) => secret.to_owned(),
+                    Err(reason) => return Ok(HttpResponse::BadRequest().body(reason)),
+                },
+                None => webhook::new_secret(),
+            };
+            Some((url.to_owned(), secret))
+        }
+        (None, Some(_)) => {
+            return Ok(HttpResponse::BadRequest().body("webhook secret needs a webhook url"));
+        }
+        (None, None) => None,
+    };
+
+    let sealed_notify_email = match &input.notify_email {
+        Some(address) => {
+            if !mailer::enabled() {
+                return Ok(HttpResponse::BadRequest()
+                    .body("email notifications are not enabled on this server"));
+            }
+            if let Err(reason) = mailer::validate_address(address) {
+                return Ok(HttpResponse::BadRequest().body(reason));
+            }
+            Some(mailer::seal_address(address)?)
+        }
+        None => None,
+    };
+
     let enc = (
         input.is_currently_encrypted.unwrap_or(false),
         input.passphrase.is_some(),
+        input.recipients.as_ref().is_some_and(|r| !r.is_empty()),
     );
 
     if input
         .discoverable
         .eq(&Some(true))
-        .then(|| (enc.0 || enc.1))
+        .then_some(enc.0 || enc.1 || enc.2)
         .eq(&Some(true))
     {
         return Ok(HttpResponse::BadRequest()
             .body("discoverability is not allowed if note is going to be encrypted"));
     }
 
-    let content_bits: Vec<u8> = if let Some(passphrase) = &input.passphrase {
-        let cryptor = RingCryptor::new();
-
-        match cryptor.seal_with_passphrase(passphrase.as_bytes(), input.content.clone().as_bytes())
-        {
-            Ok(c) => c,
-            Err(e) => match e {
-                tindercrypt::errors::Error::PassphraseTooSmall => {
-                    return Ok(HttpResponse::BadRequest().body("passphrase is too short"));
-                }
-                tindercrypt::errors::Error::BufferTooSmall => {
-                    return Ok(HttpResponse::BadRequest().body("content body is too small"))
-                }
-                _ => return Err(ServerError::TinderCryptError),
-            },
+    let envelope = if enc.0 {
+        match Envelope::parse(&input.content) {
+            Ok(envelope) => Some(envelope.metadata()),
+            Err(reason) => return Ok(HttpResponse::BadRequest().body(reason)),
         }
     } else {
-        input.content.clone().into_bytes()
+        None
+    };
+
+    if enc.2 && (enc.0 || enc.1) {
+        return Ok(HttpResponse::BadRequest()
+            .body("a note sealed to recipients cannot also be encrypted some other way"));
+    }
+
+    // the server can't tell whether files were encrypted by the client too,
+    // and would keep them as they came
+    if enc.0 && !files.is_empty() {
+        return Ok(HttpResponse::BadRequest()
+            .body("files cannot be attached to a note encrypted by the client"));
+    }
+
+    // content encrypted by the client or sealed to recipients is opened
+    // elsewhere, exactly as it was sent
+    let (plaintext, format) = if !enc.0 && !enc.2 && input.compress == Some(true) {
+        compression::compress(input.content.as_bytes())?
+    } else {
+        (
+            input.content.clone().into_bytes(),
+            compression::UNCOMPRESSED,
+        )
+    };
+
+    let content_key = input.passphrase.as_ref().map(|_| slots::new_content_key());
+    let (mut content_bits, wrapped_key): (Vec<u8>, Option<Vec<u8>>) =
+        if let (Some(passphrase), Some(key)) = (&input.passphrase, &content_key) {
+            match slots::seal_with_content_key(key, passphrase.as_bytes(), &plaintext) {
+                Ok((c, k)) => (c, Some(k)),
+                Err(e) => match e {
+                    tindercrypt::errors::Error::PassphraseTooSmall => {
+                        return Ok(HttpResponse::BadRequest().body("passphrase is too short"));
+                    }
+                    tindercrypt::errors::Error::BufferTooSmall => {
+                        return Ok(HttpResponse::BadRequest().body("content body is too small"))
+                    }
+                    _ => return Err(ServerError::TinderCryptError),
+                },
+            }
+        } else {
+            (plaintext, None)
+        };
+
+    // sealed and stored just like the real content, so opening either takes as
+    // long
+    let decoy = match (&input.duress_passphrase, &input.decoy_content) {
+        (Some(duress_passphrase), Some(decoy)) => {
+            if input.passphrase.is_none() {
+                return Ok(HttpResponse::BadRequest()
+                    .body("a duress passphrase needs the note to have a passphrase"));
+            }
+            if input.passphrase.as_ref() == Some(duress_passphrase) {
+                return Ok(HttpResponse::BadRequest()
+                    .body("duress passphrase must differ from the passphrase"));
+            }
+
+            let (plaintext, format) = if input.compress == Some(true) {
+                compression::compress(decoy.as_bytes())?
+            } else {
+                (decoy.clone().into_bytes(), compression::UNCOMPRESSED)
+            };
+
+            match slots::seal(duress_passphrase.as_bytes(), &plaintext) {
+                Ok((sealed, duress_key)) => Some((sealed, duress_key, format)),
+                Err(e) => match e {
+                    tindercrypt::errors::Error::PassphraseTooSmall => {
+                        return Ok(
+                            HttpResponse::BadRequest().body("duress passphrase is too short")
+                        );
+                    }
+                    tindercrypt::errors::Error::BufferTooSmall => {
+                        return Ok(HttpResponse::BadRequest().body("decoy content is too small"))
+                    }
+                    _ => return Err(ServerError::TinderCryptError),
+                },
+            }
+        }
+        (None, None) => None,
+        _ => {
+            return Ok(HttpResponse::BadRequest()
+                .body("duress passphrase and decoy content must be given together"));
+        }
     };
 
     let mut connection = pool.get()?;
+
+    let mut sealed_for: Vec<String> = vec![];
+    let mut recipient_keys: Vec<String> = vec![];
+    if let Some(handles) = input.recipients.as_ref().filter(|_| enc.2) {
+        sealed_for = handles.to_owned();
+        sealed_for.sort();
+        sealed_for.dedup();
+
+        if sealed_for.len() > MAX_RECIPIENTS {
+            return Ok(HttpResponse::BadRequest().body("too many recipients"));
+        }
+
+        let keys = recipient::public_keys(&mut connection, &sealed_for)?;
+        if let Some(unknown) = sealed_for
+            .iter()
+            .find(|h| !keys.iter().any(|(handle, _)| &handle == h))
+        {
+            return Ok(HttpResponse::BadRequest().body(format!("unknown recipient: {unknown}")));
+        }
+
+        recipient_keys = keys.into_iter().map(|(_, key)| key).collect();
+        content_bits = recipient::seal(&recipient_keys, input.content.as_bytes())?;
+    }
+
+    // sizes are kept as INT
+    let file_sizes = match files
+        .iter()
+        .map(|file| i32::try_from(file.content.size()))
+        .collect::<Result<Vec<i32>, _>>()
+    {
+        Ok(sizes) => sizes,
+        Err(_) => {
+            return Ok(HttpResponse::PayloadTooLarge()
+                .body(format!("files are limited to {} bytes", i32::MAX)));
+        }
+    };
+
+    let (file_names, file_contents): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .map(|file| ((file.name, file.mime_type), file.content))
+        .unzip();
+    let (decoy_bits, duress_key, decoy_bits_format) = match decoy {
+        Some((sealed, duress_key, format)) => (Some(sealed), Some(duress_key), format),
+        None => (None, None, compression::UNCOMPRESSED),
+    };
+    // files are sealed on the blocking thread pool as well, a staged upload is
+    // still read from the database as it's sealed
+    let (sealed_files, (content_bits, content_blob_key), decoy_bits) = web::block(move || {
+        let sealed_files = file_contents
+            .into_iter()
+            .map(|file| attachment::seal(file, content_key.as_deref(), &recipient_keys))
+            .collect::<Result<Vec<(Vec<u8>, Option<String>)>, ServerError>>()?;
+        let decoy_bits = decoy_bits.map(blob::offload).transpose()?;
+        Ok::<_, ServerError>((sealed_files, blob::offload(content_bits)?, decoy_bits))
+    })
+    .await??;
+
     let append_id_token = move |new_id: String, c: SystemTime| match unwraped_token {
         Some(mut jwt) => {
             jwt.claims.ids.retain(|t| t.0 != new_id);
@@ -149,31 +371,123 @@ pub async fn new(
     };
 
     let mut insert_note = |_id: String| {
-        diesel::insert_into(notes)
-            .values((
-                &id.eq(_id),
-                &title.eq(input.title.to_owned()),
-                &content.eq(&content_bits),
-                &discoverable.eq(input.discoverable.unwrap_or(false)),
-                &frontend_encryption.eq(enc.0),
-                &backend_encryption.eq(enc.1),
-                &created_at.eq(time_now),
-                &expires_at.eq(expiry_time),
-                &delete_after_read.eq(input.delete_after_read),
-                &allow_delete_with_passphrase
-                    .eq(input.allow_delete_with_passphrase.unwrap_or(false)),
-            ))
-            .returning((
-                id,
-                title,
-                backend_encryption,
-                frontend_encryption,
-                created_at,
-                expires_at,
-                delete_after_read,
-                allow_delete_with_passphrase,
-            ))
-            .get_results::<NoteInfo>(&mut connection)
+        connection.transaction::<_, diesel::result::Error, _>(|connection| {
+            let inserted = diesel::insert_into(notes)
+                .values((
+                    &id.eq(_id),
+                    &title.eq(input.title.to_owned()),
+                    &content.eq(&content_bits),
+                    &content_blob.eq(&content_blob_key),
+                    &content_format.eq(format),
+                    &content_type.eq(&note_content_type),
+                    &language.eq(&input.language),
+                    &discoverable.eq(input.discoverable.unwrap_or(false)),
+                    &search_text.eq(input
+                        .discoverable
+                        .unwrap_or(false)
+                        .then(|| search::search_text(&input.content))),
+                    &frontend_encryption.eq(enc.0),
+                    &backend_encryption.eq(enc.1),
+                    &created_at.eq(time_now),
+                    &expires_at.eq(expiry_time),
+                    &delete_after_read.eq(input.delete_after_read),
+                    &allow_delete_with_passphrase
+                        .eq(input.allow_delete_with_passphrase.unwrap_or(false)),
+                    &decoy_content.eq(decoy_bits.as_ref().map(|d| &d.0)),
+                    &decoy_blob.eq(decoy_bits.as_ref().and_then(|d| d.1.as_ref())),
+                    &decoy_format.eq(decoy_bits_format),
+                    &destroy_on_duress.eq(input.destroy_on_duress.unwrap_or(false)),
+                    &recipient_encryption.eq(enc.2),
+                    &frontend_envelope.eq(&envelope),
+                    &webhook_url.eq(webhook_hook.as_ref().map(|w| &w.0)),
+                    &webhook_secret.eq(webhook_hook.as_ref().map(|w| &w.1)),
+                    &notify_email.eq(&sealed_notify_email),
+                ))
+                .returning((
+                    id,
+                    title,
+                    backend_encryption,
+                    frontend_encryption,
+                    created_at,
+                    expires_at,
+                    delete_after_read,
+                    allow_delete_with_passphrase,
+                    recipient_encryption,
+                    content_type,
+                    language,
+                ))
+                .get_results::<NoteInfo>(connection)?;
+
+            if let Some(wrapped_key) = &wrapped_key {
+                diesel::insert_into(note_key_slots::table)
+                    .values((
+                        note_key_slots::note_id.eq(&inserted[0].id),
+                        note_key_slots::wrapped_key.eq(wrapped_key),
+                    ))
+                    .execute(connection)?;
+            }
+
+            if let Some(duress_key) = &duress_key {
+                diesel::insert_into(note_key_slots::table)
+                    .values((
+                        note_key_slots::note_id.eq(&inserted[0].id),
+                        note_key_slots::wrapped_key.eq(duress_key),
+                        note_key_slots::duress.eq(true),
+                    ))
+                    .execute(connection)?;
+            }
+
+            if !sealed_for.is_empty() {
+                diesel::insert_into(note_recipients::table)
+                    .values(
+                        sealed_for
+                            .iter()
+                            .map(|h| {
+                                (
+                                    note_recipients::note_id.eq(&inserted[0].id),
+                                    note_recipients::recipient_handle.eq(h),
+                                )
+                            })
+                            .collect::<Vec<_>>(),
+                    )
+                    .execute(connection)?;
+            }
+
+            if !note_tags_list.is_empty() {
+                diesel::insert_into(note_tags::table)
+                    .values(
+                        note_tags_list
+                            .iter()
+                            .map(|t| (note_tags::note_id.eq(&inserted[0].id), note_tags::tag.eq(t)))
+                            .collect::<Vec<_>>(),
+                    )
+                    .execute(connection)?;
+            }
+
+            if !file_names.is_empty() {
+                diesel::insert_into(note_attachments::table)
+                    .values(
+                        file_names
+                            .iter()
+                            .zip(sealed_files.iter())
+                            .zip(file_sizes.iter())
+                            .map(|(((name, mime_type), sealed), size)| {
+                                (
+                                    note_attachments::note_id.eq(&inserted[0].id),
+                                    note_attachments::name.eq(name),
+                                    note_attachments::mime_type.eq(mime_type),
+                                    note_attachments::size.eq(size),
+                                    note_attachments::content.eq(&sealed.0),
+                                    note_attachments::content_blob.eq(&sealed.1),
+                                )
+                            })
+                            .collect::<Vec<_>>(),
+                    )
+                    .execute(connection)?;
+            }
+
+            Ok(inserted)
+        })
     };
 
     if let Some(custom_id) = &input.id {
@@ -194,20 +508,23 @@ pub async fn new(
                         "title": response.title,
                         "backend_encryption": response.backend_encryption,
                         "frontend_encryption": response.frontend_encryption,
+                        "recipient_encryption": response.recipient_encryption,
+                        "content_type": response.content_type,
+                        "language": response.language,
+                        "tags": note_tags_list,
+                        "webhook_secret": webhook_hook.as_ref().map(|w| &w.1),
                         "expires_at": response.expires_at,
                         "created_at": response.created_at,
                         "token": token?
                     })));
                 }
                 Err(e) => match e {
-                    diesel::result::Error::DatabaseError(dbe_kind, _) => match dbe_kind {
-                        diesel::result::DatabaseErrorKind::UniqueViolation => {
-                            return Ok(HttpResponse::Conflict().body("id has been taken"));
-                        }
-                        _ => {
-                            return Err(ServerError::DieselError);
-                        }
-                    },
+                    diesel::result::Error::DatabaseError(
+                        diesel::result::DatabaseErrorKind::UniqueViolation,
+                        _,
+                    ) => {
+                        return Ok(HttpResponse::Conflict().body("id has been taken"));
+                    }
                     _ => {
                         return Err(ServerError::DieselError);
                     }
@@ -235,18 +552,23 @@ pub async fn new(
                     "title": response.title,
                     "backend_encryption": response.backend_encryption,
                     "frontend_encryption": response.frontend_encryption,
+                    "recipient_encryption": response.recipient_encryption,
+                    "content_type": response.content_type,
+                    "language": response.language,
+                    "tags": note_tags_list,
+                    "webhook_secret": webhook_hook.as_ref().map(|w| &w.1),
                     "expires_at": response.expires_at,
                     "created_at": response.created_at,
                     "token": token?
                 })));
             }
             Err(e) => match e {
-                diesel::result::Error::DatabaseError(dbe_kind, _) => match dbe_kind {
-                    diesel::result::DatabaseErrorKind::UniqueViolation => {
-                        continue;
-                    }
-                    _ => break Err(ServerError::DieselError),
-                },
+                diesel::result::Error::DatabaseError(
+                    diesel::result::DatabaseErrorKind::UniqueViolation,
+                    _,
+                ) => {
+                    continue;
+                }
                 _ => break Err(ServerError::DieselError),
             },
         }
@@ -283,6 +605,9 @@ pub async fn del(
             expires_at,
             delete_after_read,
             allow_delete_with_passphrase,
+            recipient_encryption,
+            content_type,
+            language,
         ))
         .find(note_id.to_owned())
         .first::<NoteInfo>(&mut connection)
@@ -301,11 +626,12 @@ pub async fn del(
             .ids
             .iter()
             .enumerate()
-            .find(|&t| t.1 .0.eq(&note_id.to_owned()));
+            .find(|&t| t.1 .0.eq(&*note_id));
 
         if let Some(res) = res {
             if note.created_at == res.1 .1 {
                 diesel::delete(notes.filter(id.eq(&note.id))).execute(&mut connection)?;
+                events::note_gone(&mut connection, Kind::Deleted, &note.id);
                 jwt.claims.ids.remove(res.0);
                 return Ok(HttpResponse::Ok().json(json!({
                     "id": note.id,
@@ -319,16 +645,20 @@ pub async fn del(
         let passphrase = json.passphrase.to_owned().unwrap();
         use crate::handlers::note::Validator;
         if passphrase.is_valid_passphrase() {
-            let note_content = notes
-                .select(content)
-                .find(note_id.to_owned())
-                .first::<Vec<u8>>(&mut connection)?;
-            let cryptor = RingCryptor::new();
-            let res = cryptor.open(passphrase.as_bytes(), &note_content);
-
-            if res.is_ok() {
+            let opens = {
+                let pool = pool.get_ref().clone();
+                let nid = note.id.clone();
+                web::block(move || {
+                    let mut connection = pool.get()?;
+                    slots::opens(&mut connection, &nid, passphrase.as_bytes())
+                })
+                .await??
+            };
+
+            if opens {
                 diesel::delete(notes.filter(id.eq(&note.id.to_owned())))
                     .execute(&mut connection)?;
+                events::note_gone(&mut connection, Kind::Deleted, &note.id);
                 return Ok(HttpResponse::Ok().finish());
             }
         }
@@ -336,3 +666,110 @@ pub async fn del(
 
     Ok(HttpResponse::Unauthorized().finish())
 }
+
+#[derive(Deserialize)]
+pub struct EditedNote {
+    content: String,
+    passphrase: Option<String>,
+    compress: Option<bool>,
+}
+
+/// Replaces the content of a note, keeping what it replaces as a version.
+/// The new content is sealed the same way as the old one, so a note behind a
+/// passphrase needs one of its passphrases to be edited.
+pub async fn edit(
+    note_id: web::Path<String>,
+    auth: web::Query<JWTAuthQuery>,
+    input: web::Json<EditedNote>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    if let Err(rejection) = slots::owned_note(&mut connection, &note_id, &auth)? {
+        return Ok(rejection);
+    }
+
+    let (is_discoverable, enc_frontend, enc_backend, enc_recipient) = notes
+        .select((
+            discoverable,
+            frontend_encryption,
+            backend_encryption,
+            recipient_encryption,
+        ))
+        .find(note_id.as_str())
+        .first::<(bool, bool, bool, bool)>(&mut connection)?;
+
+    let mut envelope = None;
+    let (sealed, format) = if enc_frontend {
+        match Envelope::parse(&input.content) {
+            Ok(parsed) => envelope = Some(parsed.metadata()),
+            Err(reason) => return Ok(HttpResponse::BadRequest().body(reason)),
+        }
+        (
+            input.content.clone().into_bytes(),
+            compression::UNCOMPRESSED,
+        )
+    } else if enc_recipient {
+        let handles = recipient::sealed_for(&mut connection, &note_id)?;
+        let keys = recipient::public_keys(&mut connection, &handles)?
+            .into_iter()
+            .map(|(_, key)| key)
+            .collect::<Vec<_>>();
+        (
+            recipient::seal(&keys, input.content.as_bytes())?,
+            compression::UNCOMPRESSED,
+        )
+    } else {
+        let (plaintext, format) = if input.compress == Some(true) {
+            compression::compress(input.content.as_bytes())?
+        } else {
+            (
+                input.content.clone().into_bytes(),
+                compression::UNCOMPRESSED,
+            )
+        };
+
+        if enc_backend {
+            let passphrase = match &input.passphrase {
+                Some(passphrase) => passphrase,
+                None => return Ok(HttpResponse::Unauthorized().body("passphrase is required")),
+            };
+            if !slots::has_slots(&mut connection, &note_id)? {
+                return Ok(HttpResponse::Conflict().body(
+                    "notes from before key slots need a key slot before they can be edited",
+                ));
+            }
+            let key = match slots::unlock(&mut connection, &note_id, passphrase.as_bytes())? {
+                Some((key, false)) => key,
+                _ => return Ok(HttpResponse::Unauthorized().body("wrong passphrase")),
+            };
+            (stream::seal(&key, &plaintext)?, format)
+        } else {
+            (plaintext, format)
+        }
+    };
+    let (sealed, sealed_blob) = web::block(move || blob::offload(sealed)).await??;
+
+    let now = SystemTime::now();
+    let version = connection.transaction::<_, diesel::result::Error, _>(|connection| {
+        let version = versions::archive(connection, &note_id, now)?;
+        diesel::update(notes.find(note_id.as_str()))
+            .set((
+                content.eq(sealed),
+                content_blob.eq(sealed_blob),
+                content_format.eq(format),
+                frontend_envelope.eq(envelope),
+                search_text.eq(is_discoverable.then(|| search::search_text(&input.content))),
+                edited_at.eq(now),
+            ))
+            .execute(connection)?;
+        Ok(version)
+    })?;
+    events::note_changed(&mut connection, Kind::Edited, &note_id)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": note_id.as_str(),
+        "version": version,
+        "edited_at": now,
+    })))
+}
@@ -1,19 +1,30 @@
-use actix_web::{web, HttpResponse};
-use diesel::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::{pg::PgConnection, prelude::*};
 use serde_derive::Deserialize;
 use serde_json::json;
 use std::time::SystemTime;
-use tindercrypt::cryptors::RingCryptor;
 
-use super::{NoteInfo, Pool};
+use super::{
+    super::recipient, attachment, compression, content_types, slots, stats, stream, tags, NoteInfo,
+    Pool,
+};
 
-use crate::{errors::ServerError, schema::notes::dsl::*};
+use crate::{
+    blob,
+    errors::ServerError,
+    events::{self, Kind},
+    mailer,
+    schema::notes::dsl::*,
+    webhook,
+};
 
 #[derive(Clone, Debug, Queryable)]
 pub struct QueryNote {
     pub id: String,
     pub title: Option<String>,
     pub content: Vec<u8>,
+    pub content_blob: Option<String>,
+    pub content_format: i16,
     pub discoverable: bool,
     pub frontend_encryption: bool,
     pub backend_encryption: bool,
@@ -21,6 +32,62 @@ pub struct QueryNote {
     pub expires_at: Option<SystemTime>,
     pub delete_after_read: Option<i32>,
     pub allow_delete_with_passphrase: bool,
+    pub recipient_encryption: bool,
+    pub content_type: String,
+    pub language: Option<String>,
+}
+
+/// A note deleted for having expired or been read for the last time
+#[derive(Queryable)]
+struct GoneNote {
+    id: String,
+    title: Option<String>,
+    notify_email: Option<Vec<u8>>,
+    first_read_at: Option<SystemTime>,
+    expires_at: Option<SystemTime>,
+}
+
+/// Deletes the notes that expired or have no reads left, only `nid` when
+/// given, returning how many were deleted. Emails for the ones that expired
+/// unread are queued in the same transaction, so a note is never deleted
+/// without its email, and only the replica that deletes it queues one.
+pub fn remove_gone(connection: &mut PgConnection, nid: Option<&str>) -> Result<usize, ServerError> {
+    let now = SystemTime::now();
+    let gone = connection.transaction(|connection| {
+        let mut removal = diesel::delete(notes)
+            .filter(expires_at.le(now).or(delete_after_read.eq(0)))
+            .into_boxed();
+        if let Some(nid) = nid {
+            removal = removal.filter(id.eq(nid));
+        }
+        let gone = removal
+            .returning((id, title, notify_email, first_read_at, expires_at))
+            .get_results::<GoneNote>(connection)?;
+
+        for note in &gone {
+            let expired = note.expires_at.is_some_and(|time| time <= now);
+            if expired && note.first_read_at.is_none() {
+                mailer::enqueue_expired_unread(
+                    connection,
+                    &note.id,
+                    note.title.as_deref(),
+                    note.notify_email.clone(),
+                )?;
+            }
+        }
+        Ok::<_, ServerError>(gone)
+    })?;
+
+    mailer::wake();
+    for note in &gone {
+        // a note with no reads left went the way a deleted one does
+        let kind = match note.expires_at {
+            Some(time) if time <= now => Kind::Expired,
+            _ => Kind::Deleted,
+        };
+        events::note_gone(connection, kind, &note.id);
+    }
+    Ok(gone.len())
 }
 
 fn return_id_not_found_response(nid: String) -> HttpResponse {
@@ -43,6 +110,9 @@ pub async fn info(
             expires_at,
             delete_after_read,
             allow_delete_with_passphrase,
+            recipient_encryption,
+            content_type,
+            language,
         ))
         .find(note_id.to_owned())
         .get_result::<NoteInfo>(&mut connection)
@@ -50,13 +120,32 @@ pub async fn info(
         Ok(note) => {
             if let Some(time) = note.expires_at {
                 if time <= SystemTime::now() {
-                    diesel::delete(notes.filter(id.eq(note_id.to_owned())))
-                        .execute(&mut connection)?;
+                    remove_gone(&mut connection, Some(&note.id))?;
                     return Ok(return_id_not_found_response(note_id.to_owned()));
                 }
             }
 
-            Ok(HttpResponse::Ok().json(json!(note)))
+            stats::record_metadata_view(&mut connection, &note.id)?;
+
+            let mut response = json!(note);
+            if note.recipient_encryption {
+                response["recipients"] = json!(recipient::sealed_for(&mut connection, &note.id)?);
+            }
+            // what's attached to a note behind a passphrase or sealed to
+            // recipients is only listed once it's opened
+            if !note.backend_encryption && !note.recipient_encryption {
+                response["attachments"] = json!(attachment::list(&mut connection, &note.id)?);
+            }
+            response["tags"] = json!(tags::list(&mut connection, &note.id)?);
+            if note.frontend_encryption {
+                response["envelope"] =
+                    json!(notes
+                        .select(frontend_envelope)
+                        .find(&note.id)
+                        .get_result::<Option<serde_json::Value>>(&mut connection)?);
+            }
+
+            Ok(HttpResponse::Ok().json(response))
         }
         Err(_) => Ok(return_id_not_found_response(note_id.to_owned())),
     }
@@ -70,6 +159,125 @@ pub struct PassphraseField {
 #[derive(Deserialize)]
 pub struct ReturnOption {
     pub secret_only: Option<bool>,
+    /// Sends the content as it is, a chunk at a time, instead of inside JSON
+    pub raw: Option<bool>,
+}
+
+/// Why a note can't be read
+pub enum Rejection {
+    NotFound,
+    WrongPassphrase,
+}
+
+impl Rejection {
+    pub fn response(&self, nid: &str) -> HttpResponse {
+        match self {
+            Rejection::NotFound => return_id_not_found_response(nid.to_owned()),
+            Rejection::WrongPassphrase => HttpResponse::Unauthorized().body("wrong passphrase"),
+        }
+    }
+}
+
+/// Loads a note and opens its content, counting it as a read.
+/// Tells why when the note can't be read.
+pub fn read(
+    connection: &mut PgConnection,
+    nid: &str,
+    passphrase: Option<&str>,
+) -> Result<Result<(QueryNote, slots::Opened), Rejection>, ServerError> {
+    // this error message has to be consistent so any bad actors will never know whether the note existed or not when requested
+
+    let mut note = match notes
+        .select((
+            id,
+            title,
+            content,
+            content_blob,
+            content_format,
+            discoverable,
+            frontend_encryption,
+            backend_encryption,
+            created_at,
+            expires_at,
+            delete_after_read,
+            allow_delete_with_passphrase,
+            recipient_encryption,
+            content_type,
+            language,
+        ))
+        .find(nid)
+        .get_result::<QueryNote>(connection)
+    {
+        Ok(note) => note,
+        Err(diesel::result::Error::NotFound) => return Ok(Err(Rejection::NotFound)),
+        Err(_) => return Err(ServerError::DieselError),
+    };
+
+    if let Some(time) = note.expires_at {
+        if time <= SystemTime::now() {
+            remove_gone(connection, Some(nid))?;
+            return Ok(Err(Rejection::NotFound));
+        }
+    }
+
+    let opened = if note.backend_encryption {
+        let opened = match passphrase {
+            Some(passphrase) => slots::open_streaming(connection, nid, passphrase.as_bytes())?,
+            None => None,
+        };
+
+        match opened {
+            Some(opened) => opened,
+            None => return Ok(Err(Rejection::WrongPassphrase)),
+        }
+    } else {
+        slots::Opened {
+            chunks: compression::decompress(
+                stream::read_in_chunks(blob::reader(
+                    std::mem::take(&mut note.content),
+                    note.content_blob.take(),
+                )?),
+                note.content_format,
+            )?,
+            duress: false,
+            key: None,
+        }
+    };
+
+    // taken in one statement, so two reads racing for the last one can't both
+    // get it
+    if note.delete_after_read.is_some() {
+        let taken = diesel::update(notes.filter(id.eq(nid)).filter(delete_after_read.gt(0)))
+            .set(delete_after_read.eq(delete_after_read - 1))
+            .execute(connection)?;
+        if taken == 0 {
+            return Ok(Err(Rejection::NotFound));
+        }
+    }
+
+    if stats::record_read(connection, nid)? {
+        mailer::enqueue_first_read(connection, nid)?;
+    }
+    webhook::enqueue_read(connection, nid)?;
+    events::note_changed(connection, Kind::Read, nid)?;
+
+    Ok(Ok((note, opened)))
+}
+
+/// [`read`] on the blocking thread pool, since opening the content may wait
+/// on the blob store
+pub async fn read_blocking(
+    pool: &Pool,
+    nid: &str,
+    passphrase: Option<String>,
+) -> Result<Result<(QueryNote, slots::Opened), Rejection>, ServerError> {
+    let pool = pool.clone();
+    let nid = nid.to_owned();
+    web::block(move || {
+        let mut connection = pool.get()?;
+        read(&mut connection, &nid, passphrase.as_deref())
+    })
+    .await?
 }
 
 pub async fn decrypt_note(
@@ -78,130 +286,179 @@ pub async fn decrypt_note(
     query: web::Query<ReturnOption>,
     pool: web::Data<Pool>,
 ) -> Result<HttpResponse, ServerError> {
-    let mut connection = pool.get()?;
-    // this error message has to be consistent so any bad actors will never know whether the note existed or not when requested
+    let (note, opened) = match read_blocking(&pool, &note_id, input.passphrase.clone()).await? {
+        Ok(opened) => opened,
+        Err(rejection) => return Ok(rejection.response(&note_id)),
+    };
+    let chunks = opened.chunks;
+    if opened.duress {
+        slots::destroy_after_duress(pool.get_ref().clone(), note.id.clone());
+    }
 
-    match notes
-        .find(note_id.to_owned())
-        .get_result::<QueryNote>(&mut connection)
-    {
-        Ok(note) => {
-            let note_content: String;
-            let mut del_invalid_note =
-                || match diesel::delete(notes.filter(id.eq(note_id.to_owned())))
-                    .execute(&mut connection)
-                {
-                    Ok(_) => return_id_not_found_response(note_id.to_owned()),
-                    Err(_) => HttpResponse::InternalServerError().finish(),
-                };
+    if query.raw == Some(true) {
+        // content only the client can open is sent as the bytes it is
+        let raw_content_type = if note.frontend_encryption || note.recipient_encryption {
+            "application/octet-stream".to_string()
+        } else if content_types::is_allowed(&note.content_type) {
+            format!("{}; charset=utf-8", note.content_type)
+        } else {
+            format!("{}; charset=utf-8", content_types::DEFAULT)
+        };
 
-            if let Some(time) = note.expires_at {
-                if time <= SystemTime::now() {
-                    return Ok(del_invalid_note());
-                }
-            }
+        return Ok(HttpResponse::Ok()
+            .content_type(raw_content_type)
+            .insert_header(("Content-Security-Policy", "sandbox; default-src 'none'"))
+            .insert_header(("X-Content-Type-Options", "nosniff"))
+            .insert_header(("Cache-Control", "no-store"))
+            .streaming(stream::body(chunks)));
+    }
 
-            if note.backend_encryption {
-                if let Some(passphrase) = &input.passphrase {
-                    let cryptor = RingCryptor::new();
-                    let res = cryptor.open(passphrase.as_bytes(), &note.content);
-
-                    match res {
-                        Ok(content_in_bytes) => note_content = String::from_utf8(content_in_bytes)?,
-                        Err(err) => match err {
-                            tindercrypt::errors::Error::PassphraseTooSmall => {
-                                return Ok(HttpResponse::Unauthorized().body("wrong passphrase"));
-                            }
-                            tindercrypt::errors::Error::DecryptionError => {
-                                return Ok(HttpResponse::Unauthorized().body("wrong passphrase"));
-                            }
-                            _ => {
-                                return Err(ServerError::TinderCryptError);
-                            }
-                        },
-                    }
-                } else {
-                    return Ok(HttpResponse::Unauthorized().body("wrong passphrase"));
-                }
-            } else {
-                note_content = String::from_utf8(note.content)?;
-            }
+    let note_content = String::from_utf8(web::block(move || stream::concat(chunks)).await??)?;
+    let mut connection = pool.get()?;
+    // looked up either way, so the decoy isn't told apart by a missing query
+    let mut note_attachments = json!(attachment::list(&mut connection, &note.id)?);
+    if opened.duress {
+        // the decoy never has attachments of its own
+        note_attachments = json!([]);
+    }
 
-            if let Some(mut query_left) = note.delete_after_read {
-                if query_left > 0 {
-                    query_left -= 1;
+    if query.secret_only.is_some().eq(&true) {
+        return Ok(HttpResponse::Ok().json(json!({
+            "content": note_content,
+        })));
+    }
 
-                    diesel::update(notes.filter(id.eq(note_id.to_owned())))
-                        .set(delete_after_read.eq(query_left))
-                        .execute(&mut connection)?;
-                } else {
-                    return Ok(return_id_not_found_response(note_id.to_owned()));
-                }
-            }
+    Ok(HttpResponse::Ok().json(json!({
+        "id": note.id,
+        "title": note.title,
+        "backend_encryption": note.backend_encryption,
+        "frontend_encryption": note.frontend_encryption,
+        "content": note_content,
+        "created_at": note.created_at,
+        "expires_at": note.expires_at,
+        "request_left": note.delete_after_read.map(|x| x - 1),
+        "allow_delete_with_passphrase": note.allow_delete_with_passphrase,
+        "recipient_encryption": note.recipient_encryption,
+        "content_type": note.content_type,
+        "language": note.language,
+        "recipients": recipient::sealed_for(&mut connection, &note.id)?,
+        "attachments": note_attachments,
+        "tags": tags::list(&mut connection, &note.id)?,
+    })))
+}
 
-            if query.secret_only.is_some().eq(&true) {
-                return Ok(HttpResponse::Ok().json(json!({
-                    "content": note_content,
-                })));
-            }
+/// Header a passphrase can be sent in, which keeps it out of the request line
+/// and anything stored along with the request
+pub const PASSPHRASE_HEADER: &str = "X-Note-Passphrase";
 
-            Ok(HttpResponse::Ok().json(json!({
-                "id": note.id,
-                "title": note.title,
-                "backend_encryption": note.backend_encryption,
-                "frontend_encryption": note.frontend_encryption,
-                "content": note_content,
-                "created_at": note.created_at,
-                "expires_at": note.expires_at,
-                "request_left": note.delete_after_read.and_then(|x| Some(x-1)),
-                "allow_delete_with_passphrase": note.allow_delete_with_passphrase,
-            })))
-        }
-        Err(err) => match err {
-            diesel::result::Error::NotFound => Ok(return_id_not_found_response(note_id.to_owned())),
-            _ => Err(ServerError::DieselError),
+pub fn passphrase_from_header(req: &HttpRequest) -> Result<Option<String>, String> {
+    match req.headers().get(PASSPHRASE_HEADER) {
+        Some(value) => match value.to_str() {
+            Ok(passphrase) => Ok(Some(passphrase.to_owned())),
+            Err(_) => Err(format!("{PASSPHRASE_HEADER} must be visible ASCII")),
         },
+        None => Ok(None),
     }
 }
 
-#[derive(Deserialize)]
-pub struct FilterParameterQuery {
-    pub title: String,
-    pub offset: Option<i64>,
-    pub limit: Option<i64>,
-}
-
-pub async fn search_by_title(
-    input: web::Query<FilterParameterQuery>,
+/// The content of a note as the body of the response, for curl and scripts.
+/// Counts as a read just like [`decrypt_note`].
+pub async fn raw(
+    req: HttpRequest,
+    note_id: web::Path<String>,
     pool: web::Data<Pool>,
 ) -> Result<HttpResponse, ServerError> {
-    let mut connection = pool.get()?;
+    let passphrase = match passphrase_from_header(&req) {
+        Ok(passphrase) => passphrase,
+        Err(reason) => return Ok(HttpResponse::BadRequest().body(reason)),
+    };
 
-    match notes
-        .select((
-            id,
-            title,
-            backend_encryption,
-            frontend_encryption,
-            created_at,
-            expires_at,
-            delete_after_read,
-            allow_delete_with_passphrase,
-        ))
-        .offset(input.0.offset.unwrap_or(0))
-        .limit(input.0.limit.unwrap_or(5))
-        .order(created_at.asc())
-        .filter(
-            title.ilike(&input.title).and(
-                backend_encryption
-                    .eq(false)
-                    .and(frontend_encryption.eq(false))
-                    .and(discoverable.eq(true)),
-            ),
-        )
-        .get_results::<NoteInfo>(&mut connection)
-    {
-        Ok(notes_vec) => Ok(HttpResponse::Ok().json(json!(notes_vec))),
-        Err(_) => Ok(HttpResponse::NotFound().finish()),
+    decrypt_note(
+        note_id,
+        web::Json(PassphraseField { passphrase }),
+        web::Query(ReturnOption {
+            secret_only: None,
+            raw: Some(true),
+        }),
+        pool,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nanoid::nanoid;
+
+    use super::*;
+    use crate::schema::email_notifications;
+
+    fn insert_note(
+        connection: &mut PgConnection,
+        expiry: Option<SystemTime>,
+        reads_left: Option<i32>,
+    ) -> String {
+        let nid = nanoid!();
+        diesel::insert_into(notes)
+            .values((
+                id.eq(&nid),
+                content.eq(b"gone".to_vec()),
+                discoverable.eq(false),
+                frontend_encryption.eq(false),
+                backend_encryption.eq(false),
+                expires_at.eq(expiry),
+                delete_after_read.eq(reads_left),
+                notify_email.eq(mailer::seal_address("owner@example.com").unwrap()),
+            ))
+            .execute(connection)
+            .unwrap();
+        nid
+    }
+
+    fn emails_for(connection: &mut PgConnection, nid: &str) -> i64 {
+        email_notifications::table
+            .filter(email_notifications::note_id.eq(nid))
+            .count()
+            .get_result::<i64>(connection)
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL, SECRET_KEY, SMTP_HOST and SMTP_FROM"]
+    fn remove_gone_queues_one_email_per_note_expiring_unread() {
+        assert!(mailer::enabled(), "SMTP_HOST is set");
+        let mut connection =
+            PgConnection::establish(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+        let expired = insert_note(
+            &mut connection,
+            Some(SystemTime::now() - Duration::from_secs(1)),
+            None,
+        );
+        let read_out = insert_note(&mut connection, None, Some(0));
+
+        assert_eq!(remove_gone(&mut connection, Some(&expired)).unwrap(), 1);
+        assert_eq!(remove_gone(&mut connection, Some(&expired)).unwrap(), 0);
+        assert_eq!(remove_gone(&mut connection, Some(&read_out)).unwrap(), 1);
+
+        assert_eq!(emails_for(&mut connection, &expired), 1);
+        assert_eq!(emails_for(&mut connection, &read_out), 0);
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL and SECRET_KEY"]
+    fn remove_gone_keeps_live_notes() {
+        let mut connection =
+            PgConnection::establish(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+        let live = insert_note(
+            &mut connection,
+            Some(SystemTime::now() + Duration::from_secs(60)),
+            Some(1),
+        );
+
+        assert_eq!(remove_gone(&mut connection, Some(&live)).unwrap(), 0);
+        diesel::delete(notes.find(&live))
+            .execute(&mut connection)
+            .unwrap();
     }
 }
@@ -0,0 +1,188 @@
+//! HTML view of notes, for readers without the web app. Markdown notes are
+//! rendered, anything else is shown as highlighted code (see [`highlight`]).
+//!
+//! Whatever the Markdown turns into goes through a strict sanitizer: no
+//! scripts, styles, forms or frames, links only to http(s) and mail, and no
+//! images unless `MARKDOWN_REMOTE_IMAGES=true`, since loading one tells its
+//! host who read the note.
+//!
+//! Notes bigger than `MAX_RENDER_SIZE` bytes are shown as escaped text
+//! instead, since rendering takes a while on those.
+use std::{sync::OnceLock, time::SystemTime};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use pulldown_cmark::{Options, Parser};
+use serde_derive::Deserialize;
+
+use super::{highlight, query, slots, stream, Pool};
+use crate::{errors::ServerError, schema::notes};
+
+const MARKDOWN: &str = "text/markdown";
+// how long shared caches may keep the page of a discoverable note
+const MAX_CACHE_AGE_IN_SECS: u64 = 300;
+
+static MAX_RENDER_SIZE: OnceLock<usize> = OnceLock::new();
+
+fn max_render_size() -> usize {
+    *MAX_RENDER_SIZE.get_or_init(|| {
+        std::env::var("MAX_RENDER_SIZE")
+            .unwrap_or("1048576".to_string())
+            .parse::<usize>()
+            .expect("must be an unsigned number of bytes")
+    })
+}
+
+fn remote_images_allowed() -> bool {
+    std::env::var("MARKDOWN_REMOTE_IMAGES").is_ok_and(|allowed| allowed == "true")
+}
+
+pub fn markdown(markdown: &str) -> String {
+    let mut unsafe_html = String::new();
+    pulldown_cmark::html::push_html(
+        &mut unsafe_html,
+        Parser::new_ext(
+            markdown,
+            Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS,
+        ),
+    );
+
+    let mut sanitizer = ammonia::Builder::default();
+    sanitizer
+        .url_schemes(["http", "https", "mailto"].into())
+        .link_rel(Some("noopener noreferrer nofollow ugc"));
+    if !remote_images_allowed() {
+        sanitizer.rm_tags(["img"]);
+    }
+
+    sanitizer.clean(&unsafe_html).to_string()
+}
+
+#[derive(Deserialize)]
+pub struct RenderOption {
+    /// `code` shows Markdown notes as their source too
+    pub mode: Option<String>,
+    pub theme: Option<String>,
+    pub line_numbers: Option<bool>,
+}
+
+/// Renders a note as a standalone HTML page. Passphrase-protected notes take
+/// the passphrase from the same header as `raw`, and every view counts as a
+/// read.
+pub async fn html(
+    req: HttpRequest,
+    note_id: web::Path<String>,
+    option: web::Query<RenderOption>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    let as_code = match option.mode.as_deref() {
+        None => None,
+        Some("code") => Some(true),
+        Some("markdown") => Some(false),
+        Some(mode) => {
+            return Ok(HttpResponse::BadRequest()
+                .body(format!("mode must be markdown or code, not {mode}")));
+        }
+    };
+    let theme_name = option.theme.as_deref().unwrap_or(highlight::DEFAULT_THEME);
+    let theme = match highlight::theme(theme_name) {
+        Some(theme) => theme,
+        None => {
+            return Ok(HttpResponse::BadRequest().body(format!(
+                "theme must be one of {}",
+                highlight::theme_names().join(", ")
+            )));
+        }
+    };
+
+    let passphrase = match query::passphrase_from_header(&req) {
+        Ok(passphrase) => passphrase,
+        Err(reason) => return Ok(HttpResponse::BadRequest().body(reason)),
+    };
+
+    // checked before reading, so a note that can't be rendered keeps its reads
+    match notes::table
+        .select((notes::frontend_encryption, notes::recipient_encryption))
+        .find(note_id.as_str())
+        .get_result::<(bool, bool)>(&mut connection)
+    {
+        Ok((frontend, recipient)) if frontend || recipient => {
+            return Ok(HttpResponse::UnprocessableEntity()
+                .body("note is encrypted for its readers, the server cannot render it"));
+        }
+        Ok(_) | Err(diesel::result::Error::NotFound) => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    let (note, opened) = match query::read_blocking(&pool, &note_id, passphrase).await? {
+        Ok(opened) => opened,
+        Err(rejection) => return Ok(rejection.response(&note_id)),
+    };
+    if opened.duress {
+        slots::destroy_after_duress(pool.get_ref().clone(), note.id.clone());
+    }
+
+    let chunks = opened.chunks;
+    let as_code = as_code.unwrap_or(note.content_type != MARKDOWN);
+    let line_numbers = option.line_numbers.unwrap_or(true);
+    let (language, content_type) = (note.language.clone(), note.content_type.clone());
+    let body = web::block(move || {
+        let text = String::from_utf8(stream::concat(chunks)?)?;
+        if text.len() > max_render_size() {
+            Ok::<_, ServerError>(format!("<pre>{}</pre>\n", ammonia::clean_text(&text)))
+        } else if as_code {
+            Ok(highlight::render(
+                &text,
+                language.as_deref(),
+                &content_type,
+                theme,
+                line_numbers,
+            )?)
+        } else {
+            Ok(markdown(&text))
+        }
+    })
+    .await??;
+    let page = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<article>\n{body}</article>\n</body>\n</html>\n",
+        ammonia::clean_text(note.title.as_deref().unwrap_or(&note.id)),
+    );
+
+    // a burnt read must not be served again from a cache, and only public
+    // notes may sit in shared ones
+    let cache_control = match note.expires_at {
+        _ if note.delete_after_read.is_some() || note.backend_encryption || !note.discoverable => {
+            "no-store".to_string()
+        }
+        Some(time) => {
+            let left = time
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+                .as_secs();
+            format!("public, max-age={}", left.min(MAX_CACHE_AGE_IN_SECS))
+        }
+        None => format!("public, max-age={MAX_CACHE_AGE_IN_SECS}"),
+    };
+
+    // highlighted code is styled inline, sanitized Markdown never is
+    let extra_sources = if as_code {
+        " style-src 'unsafe-inline';"
+    } else if remote_images_allowed() {
+        " img-src https: http:;"
+    } else {
+        ""
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header(("Cache-Control", cache_control))
+        .insert_header((
+            "Content-Security-Policy",
+            format!("default-src 'none';{extra_sources} frame-ancestors 'none'; base-uri 'none'"),
+        ))
+        .insert_header(("X-Content-Type-Options", "nosniff"))
+        .insert_header(("Referrer-Policy", "no-referrer"))
+        .body(page))
+}
@@ -0,0 +1,418 @@
+//! Full-text search over discoverable notes.
+//!
+//! Discoverable notes are never encrypted, and a plaintext copy of their
+//! content is kept in `search_text` for Postgres to index, since the content
+//! itself may be compressed or kept in the blob store. The title weighs more
+//! than the content when results are ranked.
+//!
+//! Titles are also matched by trigram similarity with `pg_trgm`, which is
+//! forgiving of typos and backs the autocomplete of titles.
+use actix_web::{web, HttpResponse};
+use diesel::{
+    pg::PgConnection,
+    prelude::*,
+    sql_types::{Array, BigInt, Bool, Float4, Int4, Nullable, Text, Timestamp, Varchar},
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::{sync::OnceLock, time::SystemTime};
+
+use super::{tags, Pool};
+
+use crate::errors::ServerError;
+
+// tsvectors are capped at 1 MiB, so only the start of long notes is indexed
+const MAX_SEARCH_TEXT: usize = 256 * 1024;
+
+// marks matches in snippets until they are escaped, private use characters
+// so no note can contain them in a way that matters
+const MATCH_START: char = '\u{E000}';
+const MATCH_END: char = '\u{E001}';
+
+const DEFAULT_RESULTS: i64 = 5;
+const MAX_RESULTS: i64 = 100;
+const MAX_SUGGESTIONS: i64 = 20;
+const MAX_TAG_COUNTS: i64 = 50;
+
+static SIMILARITY_THRESHOLD: OnceLock<f32> = OnceLock::new();
+
+fn similarity_threshold() -> f32 {
+    *SIMILARITY_THRESHOLD.get_or_init(|| {
+        let threshold = std::env::var("SEARCH_SIMILARITY_THRESHOLD")
+            .unwrap_or("0.3".to_string())
+            .parse::<f32>()
+            .expect("must be a number between 0 and 1");
+        assert!(
+            (0.0..=1.0).contains(&threshold),
+            "SEARCH_SIMILARITY_THRESHOLD must be between 0 and 1"
+        );
+        threshold
+    })
+}
+
+/// Makes the trigram operators of the current transaction match as closely
+/// as `SEARCH_SIMILARITY_THRESHOLD` asks
+fn set_similarity_threshold(connection: &mut PgConnection) -> Result<(), diesel::result::Error> {
+    let threshold = similarity_threshold().to_string();
+    diesel::sql_query(
+        "SELECT set_config('pg_trgm.similarity_threshold', $1, true), \
+           set_config('pg_trgm.word_similarity_threshold', $1, true)",
+    )
+    .bind::<Text, _>(threshold)
+    .execute(connection)?;
+    Ok(())
+}
+
+/// What of a discoverable note goes in `search_text`
+pub fn search_text(content: &str) -> String {
+    let mut end = content.len().min(MAX_SEARCH_TEXT);
+    while !content.is_char_boundary(end) {
+        end -= 1;
+    }
+    content[..end].to_string()
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    /// Words to look for, with `"quoted phrases"`, `or` and `-excluded` words
+    pub q: Option<String>,
+    /// An `ILIKE` pattern the title must match, `%` and `_` included
+    pub title: Option<String>,
+    /// Matches titles by similarity instead, for when the words are misspelled
+    pub fuzzy: Option<bool>,
+    /// Comma separated tags the notes must all have
+    pub tags: Option<String>,
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+/// The parts of a search that make up which notes match it
+struct Filter {
+    words: Option<String>,
+    title: Option<String>,
+    fuzzy: bool,
+    tags: Vec<String>,
+}
+
+impl Filter {
+    fn new(input: &SearchQuery) -> Result<Self, String> {
+        let words = input
+            .q
+            .as_deref()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(str::to_string);
+        let tags = match &input.tags {
+            Some(list) => tags::parse(list)?,
+            None => vec![],
+        };
+        Ok(Self {
+            words,
+            title: input.title.clone().filter(|pattern| !pattern.is_empty()),
+            fuzzy: input.fuzzy.unwrap_or(false),
+            tags,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.words.is_none() && self.title.is_none() && self.tags.is_empty()
+    }
+
+    /// SQL matching the notes, with the words bound to `$1`, the tags to `$2`,
+    /// the title pattern to `$3` and the current time to `$4`
+    fn condition(&self) -> String {
+        // the cleanup task may not have gotten to expired and read out notes yet
+        let mut condition = "discoverable \
+            AND NOT backend_encryption \
+            AND NOT frontend_encryption \
+            AND NOT recipient_encryption \
+            AND (expires_at IS NULL OR expires_at > $4) \
+            AND (delete_after_read IS NULL OR delete_after_read > 0)"
+            .to_string();
+        match (&self.words, self.fuzzy) {
+            (Some(_), false) => {
+                condition.push_str(" AND search_vector @@ websearch_to_tsquery('english', $1)")
+            }
+            (Some(_), true) => condition.push_str(" AND title % $1"),
+            (None, _) => (),
+        }
+        if self.title.is_some() {
+            condition.push_str(" AND title ILIKE $3");
+        }
+        if !self.tags.is_empty() {
+            condition.push_str(
+                " AND id IN ( \
+                   SELECT note_id FROM note_tags WHERE tag = ANY($2) \
+                   GROUP BY note_id HAVING count(*) = cardinality($2) \
+                 )",
+            );
+        }
+        condition
+    }
+
+    fn rank(&self) -> &'static str {
+        match (&self.words, self.fuzzy) {
+            (Some(_), false) => "ts_rank(search_vector, websearch_to_tsquery('english', $1))",
+            (Some(_), true) => "similarity(title, $1)",
+            (None, _) => "0",
+        }
+    }
+
+    fn order(&self) -> &'static str {
+        match (&self.words, &self.title) {
+            (Some(_), _) => "rank DESC, created_at ASC",
+            (None, Some(_)) => "created_at ASC",
+            // browsing by tag alone shows the newest first
+            (None, None) => "created_at DESC",
+        }
+    }
+
+    fn snippet(&self) -> String {
+        match (&self.words, self.fuzzy) {
+            (Some(_), false) => format!(
+                "ts_headline('english', coalesce(notes.search_text, ''), \
+                   websearch_to_tsquery('english', $1), \
+                   'StartSel={MATCH_START}, StopSel={MATCH_END}, MaxFragments=2, MinWords=8, MaxWords=24')"
+            ),
+            _ => "NULL".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+struct SearchResult {
+    #[diesel(sql_type = Varchar)]
+    id: String,
+    #[diesel(sql_type = Nullable<Varchar>)]
+    title: Option<String>,
+    #[diesel(sql_type = Bool)]
+    backend_encryption: bool,
+    #[diesel(sql_type = Bool)]
+    frontend_encryption: bool,
+    #[diesel(sql_type = Timestamp)]
+    created_at: SystemTime,
+    #[diesel(sql_type = Nullable<Timestamp>)]
+    expires_at: Option<SystemTime>,
+    #[diesel(sql_type = Nullable<Int4>)]
+    delete_after_read: Option<i32>,
+    #[diesel(sql_type = Bool)]
+    allow_delete_with_passphrase: bool,
+    #[diesel(sql_type = Bool)]
+    recipient_encryption: bool,
+    #[diesel(sql_type = Varchar)]
+    content_type: String,
+    #[diesel(sql_type = Nullable<Varchar>)]
+    language: Option<String>,
+    #[diesel(sql_type = Array<Text>)]
+    tags: Vec<String>,
+    #[diesel(sql_type = Float4)]
+    rank: f32,
+    #[diesel(sql_type = Nullable<Text>)]
+    snippet: Option<String>,
+}
+
+/// Escapes a snippet from `ts_headline` for HTML, wrapping matches in `<mark>`
+fn escape_snippet(snippet: &str) -> String {
+    let mut escaped = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            MATCH_START => escaped.push_str("<mark>"),
+            MATCH_END => escaped.push_str("</mark>"),
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub async fn search(
+    input: web::Query<SearchQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    let filter = match Filter::new(&input) {
+        Ok(filter) if filter.is_empty() => {
+            return Ok(
+                HttpResponse::BadRequest().body("search needs words, a title or tags to look for")
+            );
+        }
+        Ok(filter) => filter,
+        Err(reason) => return Ok(HttpResponse::BadRequest().body(reason)),
+    };
+
+    let offset = input.offset.unwrap_or(0);
+    let limit = input.limit.unwrap_or(DEFAULT_RESULTS);
+    if offset < 0 || limit < 0 {
+        return Ok(HttpResponse::BadRequest().body("offset and limit can't be negative"));
+    }
+
+    // snippets are made for the page of results only, not every match
+    let results = connection.transaction(|connection| {
+        set_similarity_threshold(connection)?;
+        diesel::sql_query(format!(
+            "SELECT hits.*, {snippet} AS snippet, \
+               ARRAY(SELECT tag FROM note_tags WHERE note_id = hits.id ORDER BY tag) AS tags \
+             FROM ( \
+               SELECT id, title, backend_encryption, frontend_encryption, created_at, expires_at, \
+                 delete_after_read, allow_delete_with_passphrase, recipient_encryption, \
+                 content_type, language, {rank}::real AS rank \
+               FROM notes \
+               WHERE {condition} \
+               ORDER BY {order} \
+               OFFSET $5 LIMIT $6 \
+             ) AS hits \
+             JOIN notes ON notes.id = hits.id \
+             ORDER BY {order}",
+            snippet = filter.snippet(),
+            rank = filter.rank(),
+            condition = filter.condition(),
+            order = filter.order(),
+        ))
+        .bind::<Text, _>(filter.words.as_deref().unwrap_or_default())
+        .bind::<Array<Text>, _>(&filter.tags)
+        .bind::<Text, _>(filter.title.as_deref().unwrap_or_default())
+        .bind::<Timestamp, _>(SystemTime::now())
+        .bind::<BigInt, _>(offset)
+        .bind::<BigInt, _>(limit.clamp(1, MAX_RESULTS))
+        .load::<SearchResult>(connection)
+    })?;
+
+    let results = results
+        .into_iter()
+        .map(|mut result| {
+            result.snippet = result.snippet.as_deref().map(escape_snippet);
+            result
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(json!(results)))
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+struct TagCount {
+    #[diesel(sql_type = Varchar)]
+    tag: String,
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+/// How many discoverable notes have each tag. Given the same parameters as
+/// [`search`], only the notes it matches are counted, which is what facets
+/// of the results are made from.
+pub async fn tag_counts(
+    input: web::Query<SearchQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    let filter = match Filter::new(&input) {
+        Ok(filter) => filter,
+        Err(reason) => return Ok(HttpResponse::BadRequest().body(reason)),
+    };
+
+    let counts = connection.transaction(|connection| {
+        set_similarity_threshold(connection)?;
+        diesel::sql_query(format!(
+            "SELECT tag, count(*) AS count \
+             FROM note_tags \
+             WHERE note_id IN (SELECT id FROM notes WHERE {condition}) \
+             GROUP BY tag \
+             ORDER BY count DESC, tag ASC \
+             LIMIT {MAX_TAG_COUNTS}",
+            condition = filter.condition(),
+        ))
+        .bind::<Text, _>(filter.words.as_deref().unwrap_or_default())
+        .bind::<Array<Text>, _>(&filter.tags)
+        .bind::<Text, _>(filter.title.as_deref().unwrap_or_default())
+        .bind::<Timestamp, _>(SystemTime::now())
+        .load::<TagCount>(connection)
+    })?;
+
+    Ok(HttpResponse::Ok().json(json!(counts)))
+}
+
+#[derive(Deserialize)]
+pub struct SuggestionQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+struct Suggestion {
+    #[diesel(sql_type = Varchar)]
+    id: String,
+    #[diesel(sql_type = Varchar)]
+    title: String,
+}
+
+/// Escapes what `LIKE` would take for wildcards
+fn escape_like(pattern: &str) -> String {
+    pattern
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Titles of discoverable notes for what has been typed so far. Titles that
+/// start with it come first, then the ones with a word most like it.
+pub async fn suggest(
+    input: web::Query<SuggestionQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    let typed = input.q.trim();
+    // a single letter has no trigrams worth matching
+    if typed.chars().count() < 2 {
+        return Ok(HttpResponse::Ok().json(json!([])));
+    }
+
+    let suggestions = connection.transaction(|connection| {
+        set_similarity_threshold(connection)?;
+        diesel::sql_query(
+            "SELECT id, title \
+             FROM notes \
+             WHERE (title ILIKE $2 OR $1 <% title) \
+               AND discoverable \
+               AND NOT backend_encryption \
+               AND NOT frontend_encryption \
+               AND NOT recipient_encryption \
+               AND (expires_at IS NULL OR expires_at > $4) \
+               AND (delete_after_read IS NULL OR delete_after_read > 0) \
+             ORDER BY title ILIKE $2 DESC, word_similarity($1, title) DESC, title ASC \
+             LIMIT $3",
+        )
+        .bind::<Text, _>(typed)
+        .bind::<Text, _>(format!("{}%", escape_like(typed)))
+        .bind::<BigInt, _>(input.limit.unwrap_or(8).clamp(1, MAX_SUGGESTIONS))
+        .bind::<Timestamp, _>(SystemTime::now())
+        .load::<Suggestion>(connection)
+    })?;
+
+    Ok(HttpResponse::Ok().json(json!(suggestions)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_like_escapes_wildcards() {
+        assert_eq!(escape_like("50% off_now"), "50\\% off\\_now");
+    }
+
+    #[test]
+    fn escape_like_escapes_the_escape_character_first() {
+        assert_eq!(escape_like("a\\%"), "a\\\\\\%");
+    }
+
+    #[test]
+    fn escape_like_leaves_plain_text_alone() {
+        assert_eq!(escape_like("zebra crossing"), "zebra crossing");
+    }
+}
@@ -0,0 +1,690 @@
+use actix_web::{web, HttpResponse};
+use diesel::{pg::PgConnection, prelude::*};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    io::{Cursor, Read},
+    time::SystemTime,
+};
+use tindercrypt::cryptors::RingCryptor;
+
+use super::{compression, stream, JWTAuthQuery, Pool, Validator};
+
+use crate::{
+    blob,
+    errors::ServerError,
+    schema::{note_attachments, note_key_slots, note_versions, notes},
+};
+
+// size of the aes-256-gcm key tindercrypt seals with by default
+const CONTENT_KEY_SIZE: usize = 32;
+
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct KeySlot {
+    pub id: i32,
+    pub created_at: SystemTime,
+}
+
+pub fn new_content_key() -> Vec<u8> {
+    let mut key = vec![0u8; CONTENT_KEY_SIZE];
+    tindercrypt::rand::fill_buf(&mut key);
+    key
+}
+
+/// Seals `plaintext` under a freshly generated content key and wraps that key
+/// with `passphrase`, returning `(sealed content, wrapped key)`.
+pub fn seal(
+    passphrase: &[u8],
+    plaintext: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), tindercrypt::errors::Error> {
+    seal_with_content_key(&new_content_key(), passphrase, plaintext)
+}
+
+/// Same as [`seal`], for when the content key also seals the attachments of
+/// the note.
+pub fn seal_with_content_key(
+    key: &[u8],
+    passphrase: &[u8],
+    plaintext: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), tindercrypt::errors::Error> {
+    let cryptor = RingCryptor::new();
+
+    let wrapped_key = cryptor.seal_with_passphrase(passphrase, key)?;
+    let sealed = stream::seal(key, plaintext)?;
+
+    Ok((sealed, wrapped_key))
+}
+
+pub fn has_slots(connection: &mut PgConnection, nid: &str) -> Result<bool, ServerError> {
+    Ok(diesel::select(diesel::dsl::exists(
+        note_key_slots::table.filter(note_key_slots::note_id.eq(nid)),
+    ))
+    .get_result::<bool>(connection)?)
+}
+
+/// Tries `passphrase` against every key slot of the note and returns the
+/// unwrapped content key of the slot it opens, along with whether that slot is
+/// the duress slot guarding the decoy content.
+pub fn unlock(
+    connection: &mut PgConnection,
+    nid: &str,
+    passphrase: &[u8],
+) -> Result<Option<(Vec<u8>, bool)>, ServerError> {
+    let wrapped_keys = note_key_slots::table
+        .select((note_key_slots::wrapped_key, note_key_slots::duress))
+        .filter(note_key_slots::note_id.eq(nid))
+        .order(note_key_slots::id.asc())
+        .get_results::<(Vec<u8>, bool)>(connection)?;
+
+    // every slot is tried even after one opens, otherwise the duress
+    // passphrase would be told apart by how long the response took
+    let cryptor = RingCryptor::new();
+    let mut unlocked = None;
+    for (wrapped_key, duress) in wrapped_keys.into_iter() {
+        match cryptor.open(passphrase, &wrapped_key) {
+            Ok(key) => {
+                if unlocked.is_none() {
+                    unlocked = Some((key, duress));
+                }
+            }
+            Err(tindercrypt::errors::Error::DecryptionError)
+            | Err(tindercrypt::errors::Error::PassphraseTooSmall) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(unlocked)
+}
+
+#[derive(Queryable)]
+struct SealedNote {
+    content: Vec<u8>,
+    content_blob: Option<String>,
+    content_format: i16,
+    decoy_content: Option<Vec<u8>>,
+    decoy_blob: Option<String>,
+    decoy_format: i16,
+}
+
+/// Opens sealed content as it is read. Content in the chunked format is opened
+/// a chunk at a time, content sealed as a whole by tindercrypt all at once.
+///
+/// Returns `None` when `secret` doesn't open the content.
+fn open_chunks(
+    secret: &[u8],
+    mut sealed: Box<dyn Read + Send>,
+) -> Result<Option<stream::Chunks>, ServerError> {
+    let mut header = vec![];
+    (&mut sealed)
+        .take(stream::HEADER_SIZE as u64)
+        .read_to_end(&mut header)?;
+    let chunked = stream::is_chunked(&header);
+    let mut sealed = Cursor::new(header).chain(sealed);
+
+    if !chunked {
+        let mut whole = vec![];
+        sealed.read_to_end(&mut whole)?;
+
+        return match RingCryptor::new().open(secret, &whole) {
+            Ok(plaintext) => Ok(Some(Box::new(std::iter::once(Ok(plaintext))))),
+            Err(tindercrypt::errors::Error::DecryptionError)
+            | Err(tindercrypt::errors::Error::PassphraseTooSmall) => Ok(None),
+            Err(e) => Err(e.into()),
+        };
+    }
+
+    // the first chunk is opened right away to tell a wrong secret apart from
+    // content that's damaged further in
+    let mut opener = stream::Opener::new(secret, sealed)?;
+    match opener.next() {
+        Some(Ok(first)) => Ok(Some(Box::new(std::iter::once(Ok(first)).chain(opener)))),
+        _ => Ok(None),
+    }
+}
+
+/// Content opened from a note
+pub struct Opened {
+    pub chunks: stream::Chunks,
+    /// Whether it's the decoy, opened with the duress passphrase
+    pub duress: bool,
+    /// The content key that opened it, which opens the attachments of the
+    /// note too. Only the real content of a note with key slots has one.
+    pub key: Option<Vec<u8>>,
+}
+
+/// Opens the content of a backend-encrypted note with any of its slot
+/// passphrases. Notes created before key slots existed have no slots and are
+/// sealed with the passphrase directly.
+///
+/// The duress passphrase opens the decoy content instead. Nothing is wiped
+/// here, see [`destroy_after_duress`].
+///
+/// Returns `None` when the passphrase doesn't open the note.
+pub fn open(
+    connection: &mut PgConnection,
+    nid: &str,
+    passphrase: &[u8],
+) -> Result<Option<Vec<u8>>, ServerError> {
+    open_streaming(connection, nid, passphrase)?
+        .map(|opened| stream::concat(opened.chunks))
+        .transpose()
+}
+
+/// Same as [`open`], handing the content out a chunk at a time
+pub fn open_streaming(
+    connection: &mut PgConnection,
+    nid: &str,
+    passphrase: &[u8],
+) -> Result<Option<Opened>, ServerError> {
+    let note = notes::table
+        .select((
+            notes::content,
+            notes::content_blob,
+            notes::content_format,
+            notes::decoy_content,
+            notes::decoy_blob,
+            notes::decoy_format,
+        ))
+        .find(nid)
+        .first::<SealedNote>(connection)?;
+
+    let slotted = has_slots(connection, nid)?;
+    let (secret, sealed, format, duress) = if slotted {
+        match unlock(connection, nid, passphrase)? {
+            Some((key, false)) => (
+                key,
+                blob::reader(note.content, note.content_blob)?,
+                note.content_format,
+                false,
+            ),
+            Some((key, true)) => match note.decoy_content {
+                Some(decoy) => (
+                    key,
+                    blob::reader(decoy, note.decoy_blob)?,
+                    note.decoy_format,
+                    true,
+                ),
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        }
+    } else {
+        (
+            passphrase.to_vec(),
+            blob::reader(note.content, note.content_blob)?,
+            note.content_format,
+            false,
+        )
+    };
+
+    let key = (slotted && !duress).then(|| secret.clone());
+    open_chunks(&secret, sealed)?
+        .map(|chunks| {
+            Ok(Opened {
+                chunks: compression::decompress(chunks, format)?,
+                duress,
+                key,
+            })
+        })
+        .transpose()
+}
+
+/// Whether `passphrase` opens the note. Only notes without key slots have
+/// their content opened for it, for the others unwrapping a key is enough.
+pub fn opens(
+    connection: &mut PgConnection,
+    nid: &str,
+    passphrase: &[u8],
+) -> Result<bool, ServerError> {
+    if has_slots(connection, nid)? {
+        Ok(unlock(connection, nid, passphrase)?.is_some())
+    } else {
+        Ok(open(connection, nid, passphrase)?.is_some())
+    }
+}
+
+/// Opens an attachment of a backend-encrypted note with the content key the
+/// note was opened with, see [`Opened::key`].
+///
+/// Returns `None` when the key doesn't open the attachment.
+pub fn open_attachment(
+    key: &[u8],
+    sealed: Box<dyn Read + Send>,
+) -> Result<Option<stream::Chunks>, ServerError> {
+    open_chunks(key, sealed)
+}
+
+/// Wipes the real content of a note just opened with its duress passphrase,
+/// if the note asks for it.
+///
+/// This happens once the response is on its way, so a duress read takes just
+/// as long as any other.
+pub fn destroy_after_duress(pool: Pool, nid: String) {
+    actix_web::rt::spawn(async move {
+        let destroyed = web::block(move || {
+            let mut connection = pool.get()?;
+            destroy_real_content(&mut connection, &nid)
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|destroyed| destroyed.map_err(|e| e.to_string()));
+        if let Err(e) = destroyed {
+            log::error!("Failed to destroy content after a duress read: {e}");
+        }
+    });
+}
+
+/// Turns the decoy into the only content of the note: the real content, its
+/// attachments, its earlier versions and every slot that could open them are
+/// dropped, and the duress slot becomes an ordinary one.
+fn destroy_real_content(connection: &mut PgConnection, nid: &str) -> Result<(), ServerError> {
+    connection.transaction::<_, diesel::result::Error, _>(|connection| {
+        // looked up again, the note may have been destroyed or deleted since
+        let (decoy, decoy_blob, decoy_format) = match notes::table
+            .select((notes::decoy_content, notes::decoy_blob, notes::decoy_format))
+            .filter(notes::destroy_on_duress.eq(true))
+            .find(nid)
+            .for_update()
+            .first::<(Option<Vec<u8>>, Option<String>, i16)>(connection)
+            .optional()?
+        {
+            Some((Some(decoy), decoy_blob, decoy_format)) => (decoy, decoy_blob, decoy_format),
+            _ => return Ok(()),
+        };
+
+        // the blob of the real content is left to blob::remove_orphans
+        diesel::update(notes::table.find(nid))
+            .set((
+                notes::content.eq(&decoy),
+                notes::content_blob.eq(decoy_blob),
+                notes::content_format.eq(decoy_format),
+                notes::decoy_content.eq(None::<Vec<u8>>),
+                notes::decoy_blob.eq(None::<String>),
+                notes::decoy_format.eq(compression::UNCOMPRESSED),
+                notes::destroy_on_duress.eq(false),
+            ))
+            .execute(connection)?;
+        diesel::delete(
+            note_key_slots::table
+                .filter(note_key_slots::note_id.eq(nid))
+                .filter(note_key_slots::duress.eq(false)),
+        )
+        .execute(connection)?;
+        diesel::delete(note_attachments::table.filter(note_attachments::note_id.eq(nid)))
+            .execute(connection)?;
+        diesel::delete(note_versions::table.filter(note_versions::note_id.eq(nid)))
+            .execute(connection)?;
+        diesel::update(note_key_slots::table.filter(note_key_slots::note_id.eq(nid)))
+            .set(note_key_slots::duress.eq(false))
+            .execute(connection)?;
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[derive(Queryable)]
+pub struct OwnedNote {
+    pub backend_encryption: bool,
+    pub content: Vec<u8>,
+}
+
+/// Loads the note and checks that the token in the query owns it.
+/// Responds with the rejection to send back when it doesn't.
+pub fn owned_note(
+    connection: &mut PgConnection,
+    nid: &str,
+    auth: &JWTAuthQuery,
+) -> Result<Result<OwnedNote, HttpResponse>, ServerError> {
+    let auth = match auth.unwrap() {
+        Some(auth) => auth,
+        None => return Ok(Err(HttpResponse::Unauthorized().finish())),
+    };
+
+    let (note_created_at, note) = match notes::table
+        .select((
+            notes::created_at,
+            (notes::backend_encryption, notes::content),
+        ))
+        .find(nid)
+        .first::<(SystemTime, OwnedNote)>(connection)
+    {
+        Ok(note) => note,
+        Err(diesel::result::Error::NotFound) => {
+            return Ok(Err(HttpResponse::NotFound().finish()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if !auth.decode()?.claims.owns(nid, note_created_at) {
+        return Ok(Err(HttpResponse::Unauthorized().finish()));
+    }
+
+    Ok(Ok(note))
+}
+
+pub async fn list(
+    note_id: web::Path<String>,
+    auth: web::Query<JWTAuthQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    if let Err(rejection) = owned_note(&mut connection, &note_id, &auth)? {
+        return Ok(rejection);
+    }
+
+    let slots = note_key_slots::table
+        .select((note_key_slots::id, note_key_slots::created_at))
+        .filter(note_key_slots::note_id.eq(note_id.as_str()))
+        .filter(note_key_slots::duress.eq(false))
+        .order(note_key_slots::id.asc())
+        .get_results::<KeySlot>(&mut connection)?;
+
+    Ok(HttpResponse::Ok().json(json!(slots)))
+}
+
+#[derive(Deserialize)]
+pub struct NewSlot {
+    passphrase: String,
+    new_passphrase: String,
+}
+
+pub async fn add(
+    note_id: web::Path<String>,
+    auth: web::Query<JWTAuthQuery>,
+    input: web::Json<NewSlot>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    let note = match owned_note(&mut connection, &note_id, &auth)? {
+        Ok(note) => note,
+        Err(rejection) => return Ok(rejection),
+    };
+
+    if !note.backend_encryption {
+        return Ok(HttpResponse::BadRequest().body("note is not protected by a passphrase"));
+    }
+
+    if !input.new_passphrase.is_valid_passphrase() {
+        return Ok(HttpResponse::BadRequest().body("new passphrase is invalid"));
+    }
+
+    let cryptor = RingCryptor::new();
+    let passphrase = input.passphrase.as_bytes();
+
+    let slot = if has_slots(&mut connection, &note_id)? {
+        let key = match unlock(&mut connection, &note_id, passphrase)? {
+            Some((key, false)) => key,
+            _ => return Ok(HttpResponse::Unauthorized().body("wrong passphrase")),
+        };
+        let wrapped_key = cryptor.seal_with_passphrase(input.new_passphrase.as_bytes(), &key)?;
+
+        diesel::insert_into(note_key_slots::table)
+            .values((
+                note_key_slots::note_id.eq(note_id.as_str()),
+                note_key_slots::wrapped_key.eq(wrapped_key),
+            ))
+            .returning((note_key_slots::id, note_key_slots::created_at))
+            .get_result::<KeySlot>(&mut connection)?
+    } else {
+        // notes sealed before key slots existed are moved over to a content key
+        // here, with the passphrase that opened them as the first slot
+        let plaintext = match cryptor.open(passphrase, &note.content) {
+            Ok(plaintext) => plaintext,
+            Err(tindercrypt::errors::Error::DecryptionError)
+            | Err(tindercrypt::errors::Error::PassphraseTooSmall) => {
+                return Ok(HttpResponse::Unauthorized().body("wrong passphrase"));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let key = new_content_key();
+        let sealed = stream::seal(&key, &plaintext)?;
+        let wrapped_keys = vec![
+            cryptor.seal_with_passphrase(passphrase, &key)?,
+            cryptor.seal_with_passphrase(input.new_passphrase.as_bytes(), &key)?,
+        ];
+
+        connection.transaction::<_, diesel::result::Error, _>(|connection| {
+            diesel::update(notes::table.find(note_id.as_str()))
+                .set(notes::content.eq(sealed))
+                .execute(connection)?;
+
+            let mut slots = diesel::insert_into(note_key_slots::table)
+                .values(
+                    wrapped_keys
+                        .into_iter()
+                        .map(|wrapped_key| {
+                            (
+                                note_key_slots::note_id.eq(note_id.as_str()),
+                                note_key_slots::wrapped_key.eq(wrapped_key),
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .returning((note_key_slots::id, note_key_slots::created_at))
+                .get_results::<KeySlot>(connection)?;
+
+            Ok(slots.remove(1))
+        })?
+    };
+
+    Ok(HttpResponse::Created().json(json!(slot)))
+}
+
+pub async fn revoke(
+    path: web::Path<(String, i32)>,
+    auth: web::Query<JWTAuthQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+    let (note_id, slot_id) = path.into_inner();
+
+    if let Err(rejection) = owned_note(&mut connection, &note_id, &auth)? {
+        return Ok(rejection);
+    }
+
+    let slot_count = note_key_slots::table
+        .filter(note_key_slots::note_id.eq(&note_id))
+        .filter(note_key_slots::duress.eq(false))
+        .count()
+        .get_result::<i64>(&mut connection)?;
+
+    if slot_count <= 1 {
+        return Ok(HttpResponse::Conflict().body("the last key slot of a note cannot be revoked"));
+    }
+
+    let deleted = diesel::delete(
+        note_key_slots::table
+            .filter(note_key_slots::id.eq(slot_id))
+            .filter(note_key_slots::note_id.eq(&note_id))
+            .filter(note_key_slots::duress.eq(false)),
+    )
+    .execute(&mut connection)?;
+
+    if deleted == 0 {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    Ok(HttpResponse::Ok().json(json!({ "id": slot_id })))
+}
+
+#[cfg(test)]
+mod tests {
+    use nanoid::nanoid;
+
+    use super::*;
+
+    fn wrap(passphrase: &[u8], key: &[u8]) -> Vec<u8> {
+        RingCryptor::new()
+            .seal_with_passphrase(passphrase, key)
+            .unwrap()
+    }
+
+    fn opened(key: &[u8], sealed: Vec<u8>) -> Option<Vec<u8>> {
+        open_chunks(key, Box::new(Cursor::new(sealed)))
+            .unwrap()
+            .map(|chunks| stream::concat(chunks).unwrap())
+    }
+
+    #[test]
+    fn seal_wraps_the_key_that_seals_the_content() {
+        let (sealed, wrapped_key) = seal(b"correct horse", b"the note").unwrap();
+        let key = RingCryptor::new()
+            .open(b"correct horse", &wrapped_key)
+            .unwrap();
+
+        assert_eq!(key.len(), CONTENT_KEY_SIZE);
+        assert!(stream::is_chunked(&sealed));
+        assert_eq!(opened(&key, sealed).unwrap(), b"the note");
+    }
+
+    #[test]
+    fn open_chunks_refuses_another_key() {
+        let key = new_content_key();
+        let sealed = stream::seal(&key, b"the note").unwrap();
+
+        assert!(opened(&new_content_key(), sealed).is_none());
+    }
+
+    #[test]
+    fn open_chunks_opens_notes_sealed_whole_with_a_passphrase() {
+        let sealed = RingCryptor::new()
+            .seal_with_passphrase(b"correct horse", b"an old note")
+            .unwrap();
+
+        assert_eq!(
+            opened(b"correct horse", sealed.clone()).unwrap(),
+            b"an old note"
+        );
+        assert!(opened(b"battery staple", sealed).is_none());
+    }
+
+    #[test]
+    fn open_attachment_takes_the_content_key() {
+        let key = new_content_key();
+        let sealed = stream::seal(&key, b"attached").unwrap();
+
+        let chunks = open_attachment(&key, Box::new(Cursor::new(sealed.clone())))
+            .unwrap()
+            .unwrap();
+        assert_eq!(stream::concat(chunks).unwrap(), b"attached");
+        assert!(
+            open_attachment(&new_content_key(), Box::new(Cursor::new(sealed)))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    /// A note with a real and a duress passphrase, returned with its content key
+    fn insert_note(connection: &mut PgConnection) -> (String, Vec<u8>) {
+        let nid = nanoid!();
+        let key = new_content_key();
+        let duress_key = new_content_key();
+        diesel::insert_into(notes::table)
+            .values((
+                notes::id.eq(&nid),
+                notes::content.eq(stream::seal(&key, b"real").unwrap()),
+                notes::decoy_content.eq(stream::seal(&duress_key, b"decoy").unwrap()),
+                notes::destroy_on_duress.eq(true),
+                notes::discoverable.eq(false),
+                notes::frontend_encryption.eq(false),
+                notes::backend_encryption.eq(true),
+            ))
+            .execute(connection)
+            .unwrap();
+        diesel::insert_into(note_key_slots::table)
+            .values(vec![
+                (
+                    note_key_slots::note_id.eq(&nid),
+                    note_key_slots::wrapped_key.eq(wrap(b"real passphrase", &key)),
+                    note_key_slots::duress.eq(false),
+                ),
+                (
+                    note_key_slots::note_id.eq(&nid),
+                    note_key_slots::wrapped_key.eq(wrap(b"duress passphrase", &duress_key)),
+                    note_key_slots::duress.eq(true),
+                ),
+            ])
+            .execute(connection)
+            .unwrap();
+        (nid, key)
+    }
+
+    fn read(
+        connection: &mut PgConnection,
+        nid: &str,
+        passphrase: &[u8],
+    ) -> Option<(Vec<u8>, bool)> {
+        open_streaming(connection, nid, passphrase)
+            .unwrap()
+            .map(|opened| (stream::concat(opened.chunks).unwrap(), opened.duress))
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn slots_open_the_real_content_or_the_decoy() {
+        let mut connection =
+            PgConnection::establish(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+        let (nid, key) = insert_note(&mut connection);
+
+        assert_eq!(
+            unlock(&mut connection, &nid, b"real passphrase").unwrap(),
+            Some((key.clone(), false))
+        );
+        assert_eq!(
+            unlock(&mut connection, &nid, b"duress passphrase")
+                .unwrap()
+                .map(|(_, duress)| duress),
+            Some(true)
+        );
+        assert_eq!(
+            unlock(&mut connection, &nid, b"wrong passphrase").unwrap(),
+            None
+        );
+
+        let opened = open_streaming(&mut connection, &nid, b"real passphrase")
+            .unwrap()
+            .unwrap();
+        assert_eq!(opened.key, Some(key));
+        assert_eq!(
+            read(&mut connection, &nid, b"real passphrase"),
+            Some((b"real".to_vec(), false))
+        );
+        assert_eq!(
+            read(&mut connection, &nid, b"duress passphrase"),
+            Some((b"decoy".to_vec(), true))
+        );
+        assert!(read(&mut connection, &nid, b"wrong passphrase").is_none());
+
+        assert!(opens(&mut connection, &nid, b"real passphrase").unwrap());
+        assert!(opens(&mut connection, &nid, b"duress passphrase").unwrap());
+        assert!(!opens(&mut connection, &nid, b"wrong passphrase").unwrap());
+
+        diesel::delete(notes::table.find(&nid))
+            .execute(&mut connection)
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn destroy_real_content_leaves_only_the_decoy() {
+        let mut connection =
+            PgConnection::establish(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+        let (nid, _) = insert_note(&mut connection);
+
+        destroy_real_content(&mut connection, &nid).unwrap();
+
+        assert!(read(&mut connection, &nid, b"real passphrase").is_none());
+        assert_eq!(
+            read(&mut connection, &nid, b"duress passphrase"),
+            Some((b"decoy".to_vec(), false))
+        );
+
+        diesel::delete(notes::table.find(&nid))
+            .execute(&mut connection)
+            .unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+//! How often a note has been looked at, for its owner. Only counts and times
+//! are kept, nothing about who the readers were.
+use actix_web::{web, HttpResponse};
+use diesel::{pg::PgConnection, prelude::*};
+use serde_derive::Serialize;
+use serde_json::json;
+use std::time::SystemTime;
+
+use super::{slots, JWTAuthQuery, Pool};
+
+use crate::{errors::ServerError, schema::notes};
+
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct Stats {
+    /// Times the metadata of the note was looked up
+    pub metadata_views: i64,
+    /// Times the content was read, counting every way of reading it
+    pub reads: i64,
+    pub first_read_at: Option<SystemTime>,
+    pub last_read_at: Option<SystemTime>,
+    pub delete_after_read: Option<i32>,
+}
+
+pub fn record_metadata_view(connection: &mut PgConnection, nid: &str) -> Result<(), ServerError> {
+    diesel::update(notes::table.find(nid))
+        .set(notes::metadata_view_count.eq(notes::metadata_view_count + 1))
+        .execute(connection)?;
+    Ok(())
+}
+
+/// Counts a read of the note, telling whether it was the first one
+pub fn record_read(connection: &mut PgConnection, nid: &str) -> Result<bool, ServerError> {
+    let now = SystemTime::now();
+    let first = diesel::update(
+        notes::table
+            .find(nid)
+            .filter(notes::first_read_at.is_null()),
+    )
+    .set(notes::first_read_at.eq(now))
+    .execute(connection)?
+        > 0;
+    diesel::update(notes::table.find(nid))
+        .set((
+            notes::view_count.eq(notes::view_count + 1),
+            notes::last_read_at.eq(now),
+        ))
+        .execute(connection)?;
+    Ok(first)
+}
+
+pub async fn get(
+    note_id: web::Path<String>,
+    auth: web::Query<JWTAuthQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    if let Err(rejection) = slots::owned_note(&mut connection, &note_id, &auth)? {
+        return Ok(rejection);
+    }
+
+    let stats = notes::table
+        .select((
+            notes::metadata_view_count,
+            notes::view_count,
+            notes::first_read_at,
+            notes::last_read_at,
+            notes::delete_after_read,
+        ))
+        .find(note_id.as_str())
+        .get_result::<Stats>(&mut connection)?;
+
+    Ok(HttpResponse::Ok().json(json!(stats)))
+}
@@ -0,0 +1,462 @@
+//! Chunked AEAD format for content sealed under a content key, so it can be
+//! opened and sent a chunk at a time instead of all at once.
+//!
+//! ```text
+//! magic "HMS\x01" | nonce prefix (7 bytes) | chunk | chunk | ... | last chunk
+//! ```
+//!
+//! Every chunk is [`CHUNK_SIZE`] bytes of plaintext sealed with AES-256-GCM
+//! (so [`CHUNK_SIZE`] + 16 bytes), except the last one which may be shorter.
+//! The nonce of a chunk is the prefix, a 32-bit big-endian counter and a byte
+//! set to 1 only for the last chunk, which stops chunks from being reordered,
+//! dropped or cut off at the end without it being noticed.
+use std::io::{self, Read};
+
+use actix_web::web;
+use futures_util::Stream;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use tindercrypt::errors::Error;
+
+use crate::errors::ServerError;
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+const MAGIC: &[u8; 4] = b"HMS\x01";
+const PREFIX_SIZE: usize = 7;
+const TAG_SIZE: usize = 16;
+const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_SIZE;
+pub const HEADER_SIZE: usize = MAGIC.len() + PREFIX_SIZE;
+
+fn cipher(key: &[u8]) -> Result<LessSafeKey, Error> {
+    UnboundKey::new(&AES_256_GCM, key)
+        .map(LessSafeKey::new)
+        .map_err(|_| Error::KeySizeMismatch)
+}
+
+fn nonce(prefix: &[u8; PREFIX_SIZE], counter: u32, last: bool) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Whether `header`, the first bytes of some sealed content, is in this format
+/// rather than a whole tindercrypt message
+pub fn is_chunked(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+pub fn seal(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut sealed = Vec::with_capacity(sealed_size(plaintext.len()));
+    Sealer::new(key, plaintext)?
+        .read_to_end(&mut sealed)
+        .map_err(|_| Error::BufferTooSmall)?;
+    Ok(sealed)
+}
+
+/// How big `plaintext_size` bytes are once sealed
+pub fn sealed_size(plaintext_size: usize) -> usize {
+    HEADER_SIZE + plaintext_size + plaintext_size.div_ceil(CHUNK_SIZE).max(1) * TAG_SIZE
+}
+
+/// Seals what `reader` holds as it is read, in the same format as [`seal`]
+pub struct Sealer<R> {
+    cipher: LessSafeKey,
+    prefix: [u8; PREFIX_SIZE],
+    reader: R,
+    counter: u32,
+    sealed: io::Cursor<Vec<u8>>,
+    // the first byte of the next chunk, read to tell whether a chunk is the last
+    lookahead: Option<u8>,
+    done: bool,
+}
+
+impl<R: Read> Sealer<R> {
+    pub fn new(key: &[u8], reader: R) -> Result<Self, Error> {
+        let mut prefix = [0u8; PREFIX_SIZE];
+        tindercrypt::rand::fill_buf(&mut prefix);
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&prefix);
+
+        Ok(Self {
+            cipher: cipher(key)?,
+            prefix,
+            reader,
+            counter: 0,
+            sealed: io::Cursor::new(header),
+            lookahead: None,
+            done: false,
+        })
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+        // a whole chunk and one byte past it
+        let mut chunk = Vec::with_capacity(SEALED_CHUNK_SIZE + 1);
+        chunk.extend(self.lookahead.take());
+        (&mut self.reader)
+            .take((CHUNK_SIZE + 1 - chunk.len()) as u64)
+            .read_to_end(&mut chunk)?;
+
+        let last = chunk.len() <= CHUNK_SIZE;
+        if !last {
+            self.lookahead = chunk.pop();
+        }
+
+        let tag = self
+            .cipher
+            .seal_in_place_separate_tag(
+                nonce(&self.prefix, self.counter, last),
+                Aad::empty(),
+                &mut chunk,
+            )
+            .map_err(|_| io::Error::other("chunk failed to seal"))?;
+        chunk.extend_from_slice(tag.as_ref());
+
+        self.done = last;
+        self.counter = self.counter.checked_add(1).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "content is too long to seal")
+        })?;
+
+        Ok(chunk)
+    }
+}
+
+impl<R: Read> Read for Sealer<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.sealed.read(buf)?;
+            if read > 0 || buf.is_empty() || self.done {
+                return Ok(read);
+            }
+
+            self.sealed = io::Cursor::new(self.next_chunk()?);
+        }
+    }
+}
+
+/// Plaintext handed out a chunk at a time as it is opened
+pub type Chunks = Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>;
+
+/// Hands out content that isn't sealed in chunks of the same size
+pub fn read_in_chunks<R: Read + Send + 'static>(mut reader: R) -> Chunks {
+    let mut done = false;
+    Box::new(std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        match (&mut reader)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut chunk)
+        {
+            Ok(0) => None,
+            Ok(read) => {
+                done = read < CHUNK_SIZE;
+                Some(Ok(chunk))
+            }
+            Err(e) => {
+                done = true;
+                Some(Err(e))
+            }
+        }
+    }))
+}
+
+/// Reads [`Chunks`] back as one continuous stream
+pub struct ChunksReader {
+    chunks: Chunks,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl ChunksReader {
+    pub fn new(chunks: Chunks) -> Self {
+        Self {
+            chunks,
+            current: io::Cursor::new(vec![]),
+        }
+    }
+}
+
+impl Read for ChunksReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match self.chunks.next() {
+                Some(chunk) => self.current = io::Cursor::new(chunk?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// The body of a response sending `chunks`. Every chunk is read on the
+/// blocking thread pool, since reading one may wait on the blob store.
+pub fn body(chunks: Chunks) -> impl Stream<Item = io::Result<web::Bytes>> {
+    futures_util::stream::unfold(Some(chunks), |chunks| async move {
+        let mut chunks = chunks?;
+        let (chunk, chunks) = match web::block(move || (chunks.next(), chunks)).await {
+            Ok(next) => next,
+            Err(e) => return Some((Err(io::Error::other(e.to_string())), None)),
+        };
+        Some((chunk?.map(web::Bytes::from), Some(chunks)))
+    })
+}
+
+/// Collects every chunk of `chunks`
+pub fn concat(chunks: Chunks) -> Result<Vec<u8>, ServerError> {
+    let mut plaintext = vec![];
+    for chunk in chunks {
+        plaintext.extend(chunk?);
+    }
+    Ok(plaintext)
+}
+
+/// Opens sealed content chunk by chunk as it is read
+pub struct Opener<R> {
+    cipher: LessSafeKey,
+    prefix: [u8; PREFIX_SIZE],
+    reader: R,
+    counter: u32,
+    // the first byte of the next chunk, read to tell whether a chunk is the last
+    lookahead: Option<u8>,
+    done: bool,
+}
+
+impl<R: Read> Opener<R> {
+    pub fn new(key: &[u8], mut reader: R) -> Result<Self, Error> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| Error::MetadataMissing)?;
+        if !is_chunked(&header) {
+            return Err(Error::MetadataInvalid);
+        }
+
+        let mut prefix = [0u8; PREFIX_SIZE];
+        prefix.copy_from_slice(&header[MAGIC.len()..]);
+
+        Ok(Self {
+            cipher: cipher(key)?,
+            prefix,
+            reader,
+            counter: 0,
+            lookahead: None,
+            done: false,
+        })
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+        // a whole sealed chunk and one byte past it
+        let mut chunk = Vec::with_capacity(SEALED_CHUNK_SIZE + 1);
+        chunk.extend(self.lookahead.take());
+        (&mut self.reader)
+            .take((SEALED_CHUNK_SIZE + 1 - chunk.len()) as u64)
+            .read_to_end(&mut chunk)?;
+
+        let last = chunk.len() <= SEALED_CHUNK_SIZE;
+        if !last {
+            self.lookahead = chunk.pop();
+        }
+        if chunk.len() < TAG_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "sealed content is cut short",
+            ));
+        }
+
+        let plaintext_len = self
+            .cipher
+            .open_in_place(
+                nonce(&self.prefix, self.counter, last),
+                Aad::empty(),
+                &mut chunk,
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "chunk failed to open"))?
+            .len();
+        chunk.truncate(plaintext_len);
+
+        self.done = last;
+        self.counter = self.counter.checked_add(1).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "sealed content is too long")
+        })?;
+
+        Ok(chunk)
+    }
+}
+
+impl<R: Read> Iterator for Opener<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let chunk = self.next_chunk();
+        if chunk.is_err() {
+            self.done = true;
+        }
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn plaintext(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn open(key: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let opener = Opener::new(key, sealed).map_err(|e| io::Error::other(e.to_string()))?;
+        let mut opened = vec![];
+        for chunk in opener {
+            opened.extend(chunk?);
+        }
+        Ok(opened)
+    }
+
+    #[test]
+    fn seal_round_trips() {
+        for size in [
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE + 5,
+        ] {
+            let plaintext = plaintext(size);
+            let sealed = seal(&KEY, &plaintext).unwrap();
+            assert!(is_chunked(&sealed));
+            assert_eq!(sealed.len(), sealed_size(size), "size {size}");
+            assert_eq!(open(&KEY, &sealed).unwrap(), plaintext, "size {size}");
+        }
+    }
+
+    #[test]
+    fn seal_round_trips_an_empty_payload() {
+        let sealed = seal(&KEY, &[]).unwrap();
+        assert_eq!(sealed.len(), HEADER_SIZE + TAG_SIZE);
+        assert_eq!(sealed.len(), sealed_size(0));
+        assert_eq!(open(&KEY, &sealed).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn sealer_reads_a_little_at_a_time() {
+        // hands out at most 1000 bytes per read, like a socket would
+        struct Trickle(io::Cursor<Vec<u8>>);
+        impl Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = buf.len().min(1000);
+                self.0.read(&mut buf[..len])
+            }
+        }
+
+        let plaintext = plaintext(2 * CHUNK_SIZE + 17);
+        let mut sealed = vec![];
+        Sealer::new(&KEY, Trickle(io::Cursor::new(plaintext.clone())))
+            .unwrap()
+            .read_to_end(&mut sealed)
+            .unwrap();
+        assert_eq!(sealed.len(), sealed_size(plaintext.len()));
+        assert_eq!(open(&KEY, &sealed).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn open_fails_with_a_flipped_byte() {
+        let sealed = seal(&KEY, &plaintext(2 * CHUNK_SIZE + 5)).unwrap();
+        for at in [
+            HEADER_SIZE,
+            HEADER_SIZE + SEALED_CHUNK_SIZE + 3,
+            sealed.len() - 1,
+        ] {
+            let mut flipped = sealed.clone();
+            flipped[at] ^= 1;
+            assert!(open(&KEY, &flipped).is_err(), "flipped at {at}");
+        }
+    }
+
+    #[test]
+    fn open_fails_with_a_flipped_nonce_prefix() {
+        let mut sealed = seal(&KEY, &plaintext(10)).unwrap();
+        sealed[MAGIC.len()] ^= 1;
+        assert!(open(&KEY, &sealed).is_err());
+    }
+
+    #[test]
+    fn open_fails_without_the_last_chunk() {
+        let sealed = seal(&KEY, &plaintext(2 * CHUNK_SIZE + 5)).unwrap();
+        let truncated = &sealed[..HEADER_SIZE + 2 * SEALED_CHUNK_SIZE];
+        assert!(open(&KEY, truncated).is_err());
+    }
+
+    #[test]
+    fn open_fails_when_cut_inside_a_chunk() {
+        let sealed = seal(&KEY, &plaintext(CHUNK_SIZE + 5)).unwrap();
+        assert!(open(&KEY, &sealed[..sealed.len() - 1]).is_err());
+        assert!(open(&KEY, &sealed[..HEADER_SIZE + TAG_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn open_fails_with_reordered_chunks() {
+        let sealed = seal(&KEY, &plaintext(3 * CHUNK_SIZE)).unwrap();
+        let chunk = |i: usize| {
+            &sealed[HEADER_SIZE + i * SEALED_CHUNK_SIZE..HEADER_SIZE + (i + 1) * SEALED_CHUNK_SIZE]
+        };
+
+        let mut swapped = sealed[..HEADER_SIZE].to_vec();
+        swapped.extend_from_slice(chunk(1));
+        swapped.extend_from_slice(chunk(0));
+        swapped.extend_from_slice(chunk(2));
+        assert!(open(&KEY, &swapped).is_err());
+    }
+
+    #[test]
+    fn open_fails_with_a_duplicated_chunk() {
+        let sealed = seal(&KEY, &plaintext(2 * CHUNK_SIZE)).unwrap();
+        let first = &sealed[HEADER_SIZE..HEADER_SIZE + SEALED_CHUNK_SIZE];
+
+        let mut duplicated = sealed[..HEADER_SIZE + SEALED_CHUNK_SIZE].to_vec();
+        duplicated.extend_from_slice(first);
+        duplicated.extend_from_slice(&sealed[HEADER_SIZE + SEALED_CHUNK_SIZE..]);
+        assert!(open(&KEY, &duplicated).is_err());
+    }
+
+    #[test]
+    fn open_fails_with_another_key() {
+        let sealed = seal(&KEY, &plaintext(10)).unwrap();
+        assert!(open(&[8; 32], &sealed).is_err());
+    }
+
+    #[test]
+    fn opener_rejects_content_in_another_format() {
+        assert!(matches!(
+            Opener::new(&KEY, &b"not chunked at all"[..]),
+            Err(Error::MetadataInvalid)
+        ));
+        assert!(matches!(
+            Opener::new(&KEY, &MAGIC[..]),
+            Err(Error::MetadataMissing)
+        ));
+    }
+
+    #[test]
+    fn read_in_chunks_and_back() {
+        let plaintext = plaintext(2 * CHUNK_SIZE + 3);
+        let chunks = read_in_chunks(io::Cursor::new(plaintext.clone()));
+        let mut read = vec![];
+        ChunksReader::new(chunks).read_to_end(&mut read).unwrap();
+        assert_eq!(read, plaintext);
+    }
+}
@@ -0,0 +1,114 @@
+//! Tags a note is filed under, for browsing discoverable notes by topic.
+//!
+//! Tags are lowercased and may only hold letters, digits, `-` and `_`, so the
+//! same topic isn't spread over several spellings.
+use diesel::{pg::PgConnection, prelude::*};
+
+use crate::{errors::ServerError, schema::note_tags};
+
+pub const MAX_TAGS: usize = 10;
+pub const MAX_TAG_LENGTH: usize = 32;
+
+/// Normalizes the tags of a note, telling what's wrong with the first one
+/// that can't be
+pub fn normalize(tags: &[String]) -> Result<Vec<String>, String> {
+    let mut normalized = vec![];
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() {
+            return Err("tags cannot be empty".to_string());
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH {
+            return Err(format!(
+                "tag {tag} is longer than {MAX_TAG_LENGTH} characters"
+            ));
+        }
+        if !tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("tag {tag} may only hold letters, digits, - and _"));
+        }
+        normalized.push(tag);
+    }
+
+    normalized.sort();
+    normalized.dedup();
+    if normalized.len() > MAX_TAGS {
+        return Err(format!("a note can have at most {MAX_TAGS} tags"));
+    }
+
+    Ok(normalized)
+}
+
+/// Tags given as a comma separated list, as in query strings
+pub fn parse(list: &str) -> Result<Vec<String>, String> {
+    normalize(
+        &list
+            .split(',')
+            .filter(|tag| !tag.trim().is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>(),
+    )
+}
+
+pub fn list(connection: &mut PgConnection, nid: &str) -> Result<Vec<String>, ServerError> {
+    Ok(note_tags::table
+        .select(note_tags::tag)
+        .filter(note_tags::note_id.eq(nid))
+        .order(note_tags::tag.asc())
+        .get_results::<String>(connection)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(list: &[&str]) -> Vec<String> {
+        list.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn normalize_lowercases_sorts_and_dedups() {
+        assert_eq!(
+            normalize(&tags(&[" Rust ", "go", "rust"])),
+            Ok(tags(&["go", "rust"]))
+        );
+    }
+
+    #[test]
+    fn normalize_rejects_empty_tags() {
+        assert!(normalize(&tags(&["  "])).is_err());
+    }
+
+    #[test]
+    fn normalize_rejects_long_tags() {
+        let long = "a".repeat(MAX_TAG_LENGTH + 1);
+        assert!(normalize(&tags(&[&long])).is_err());
+        let longest = "a".repeat(MAX_TAG_LENGTH);
+        assert!(normalize(&tags(&[&longest])).is_ok());
+    }
+
+    #[test]
+    fn normalize_rejects_punctuation() {
+        assert!(normalize(&tags(&["c++"])).is_err());
+        assert!(normalize(&tags(&["snake_case-tag", "日本語"])).is_ok());
+    }
+
+    #[test]
+    fn normalize_counts_tags_after_dedup() {
+        let many = (0..MAX_TAGS).map(|i| format!("t{i}")).collect::<Vec<_>>();
+        let mut repeated = many.clone();
+        repeated.push("T0".to_string());
+        assert!(normalize(&repeated).is_ok());
+
+        let mut too_many = many;
+        too_many.push("one-more".to_string());
+        assert!(normalize(&too_many).is_err());
+    }
+
+    #[test]
+    fn parse_skips_empty_entries() {
+        assert_eq!(parse("a,, b ,"), Ok(tags(&["a", "b"])));
+    }
+}
@@ -0,0 +1,215 @@
+//! Earlier contents of edited notes, kept sealed exactly as they were stored so
+//! restoring one needs no passphrase. Versions expire with their note, and
+//! only the latest `MAX_NOTE_VERSIONS` of a note are kept.
+use actix_web::{web, HttpResponse};
+use diesel::{pg::PgConnection, prelude::*};
+use serde_derive::Serialize;
+use serde_json::json;
+use std::{sync::OnceLock, time::SystemTime};
+
+use super::{compression, search, slots, stream, JWTAuthQuery, Pool};
+
+use crate::{
+    blob,
+    errors::ServerError,
+    events,
+    schema::{note_versions, notes},
+};
+
+static MAX_VERSIONS: OnceLock<i64> = OnceLock::new();
+
+fn max_versions() -> i64 {
+    *MAX_VERSIONS.get_or_init(|| {
+        std::env::var("MAX_NOTE_VERSIONS")
+            .unwrap_or("20".to_string())
+            .parse::<i64>()
+            .expect("must be a number of versions")
+    })
+}
+
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct Version {
+    pub id: i32,
+    /// When this content was stored
+    pub written_at: SystemTime,
+    /// When it stopped being the content of the note
+    pub replaced_at: SystemTime,
+}
+
+/// Sealed content as it sits in its row
+#[derive(Queryable)]
+struct StoredContent {
+    content: Vec<u8>,
+    content_blob: Option<String>,
+    content_format: i16,
+    frontend_envelope: Option<serde_json::Value>,
+}
+
+/// Keeps the current content of the note as a version, to be called in the
+/// same transaction that replaces it. Returns the id of the new version.
+pub fn archive(
+    connection: &mut PgConnection,
+    nid: &str,
+    now: SystemTime,
+) -> Result<i32, diesel::result::Error> {
+    let (created_at, edited_at, expires_at, current) = notes::table
+        .select((
+            notes::created_at,
+            notes::edited_at,
+            notes::expires_at,
+            (
+                notes::content,
+                notes::content_blob,
+                notes::content_format,
+                notes::frontend_envelope,
+            ),
+        ))
+        .find(nid)
+        .for_update()
+        .first::<(
+            SystemTime,
+            Option<SystemTime>,
+            Option<SystemTime>,
+            StoredContent,
+        )>(connection)?;
+
+    let version = diesel::insert_into(note_versions::table)
+        .values((
+            note_versions::note_id.eq(nid),
+            note_versions::content.eq(current.content),
+            note_versions::content_blob.eq(current.content_blob),
+            note_versions::content_format.eq(current.content_format),
+            note_versions::frontend_envelope.eq(current.frontend_envelope),
+            note_versions::written_at.eq(edited_at.unwrap_or(created_at)),
+            note_versions::replaced_at.eq(now),
+            note_versions::expires_at.eq(expires_at),
+        ))
+        .returning(note_versions::id)
+        .get_result::<i32>(connection)?;
+
+    let kept = note_versions::table
+        .select(note_versions::id)
+        .filter(note_versions::note_id.eq(nid))
+        .order(note_versions::id.desc())
+        .limit(max_versions())
+        .get_results::<i32>(connection)?;
+    diesel::delete(
+        note_versions::table
+            .filter(note_versions::note_id.eq(nid))
+            .filter(diesel::dsl::not(note_versions::id.eq_any(kept))),
+    )
+    .execute(connection)?;
+
+    Ok(version)
+}
+
+/// Deletes the versions that expired along with their note, returning how many
+/// were deleted
+pub fn remove_expired(connection: &mut PgConnection) -> Result<usize, ServerError> {
+    Ok(
+        diesel::delete(
+            note_versions::table.filter(note_versions::expires_at.le(SystemTime::now())),
+        )
+        .execute(connection)?,
+    )
+}
+
+pub async fn list(
+    note_id: web::Path<String>,
+    auth: web::Query<JWTAuthQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    if let Err(rejection) = slots::owned_note(&mut connection, &note_id, &auth)? {
+        return Ok(rejection);
+    }
+
+    let versions = note_versions::table
+        .select((
+            note_versions::id,
+            note_versions::written_at,
+            note_versions::replaced_at,
+        ))
+        .filter(note_versions::note_id.eq(note_id.as_str()))
+        .order(note_versions::id.desc())
+        .get_results::<Version>(&mut connection)?;
+
+    Ok(HttpResponse::Ok().json(json!(versions)))
+}
+
+/// Brings a version back as the content of the note. The content it replaces
+/// becomes a version of its own, so a restore can be undone like any edit.
+pub async fn restore(
+    path: web::Path<(String, i32)>,
+    auth: web::Query<JWTAuthQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+    let (note_id, version_id) = path.into_inner();
+
+    if let Err(rejection) = slots::owned_note(&mut connection, &note_id, &auth)? {
+        return Ok(rejection);
+    }
+
+    let version = match note_versions::table
+        .select((
+            note_versions::content,
+            note_versions::content_blob,
+            note_versions::content_format,
+            note_versions::frontend_envelope,
+        ))
+        .filter(note_versions::id.eq(version_id))
+        .filter(note_versions::note_id.eq(&note_id))
+        .first::<StoredContent>(&mut connection)
+    {
+        Ok(version) => version,
+        Err(diesel::result::Error::NotFound) => return Ok(HttpResponse::NotFound().finish()),
+        Err(e) => return Err(e.into()),
+    };
+
+    // discoverable notes are never encrypted, so their versions read as is
+    let is_discoverable = notes::table
+        .select(notes::discoverable)
+        .find(&note_id)
+        .first::<bool>(&mut connection)?;
+    let text = if is_discoverable {
+        let (sealed, sealed_blob) = (version.content.clone(), version.content_blob.clone());
+        let format = version.content_format;
+        let restored = web::block(move || {
+            let sealed = blob::reader(sealed, sealed_blob)?;
+            stream::concat(compression::decompress(
+                stream::read_in_chunks(sealed),
+                format,
+            )?)
+        })
+        .await??;
+        Some(search::search_text(&String::from_utf8(restored)?))
+    } else {
+        None
+    };
+
+    let now = SystemTime::now();
+    let archived = connection.transaction::<_, diesel::result::Error, _>(|connection| {
+        let archived = archive(connection, &note_id, now)?;
+        diesel::update(notes::table.find(&note_id))
+            .set((
+                notes::content.eq(version.content),
+                notes::content_blob.eq(version.content_blob),
+                notes::content_format.eq(version.content_format),
+                notes::frontend_envelope.eq(version.frontend_envelope),
+                notes::search_text.eq(text),
+                notes::edited_at.eq(now),
+            ))
+            .execute(connection)
+            .map(|_| archived)
+    })?;
+    events::note_changed(&mut connection, events::Kind::Edited, &note_id)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": note_id,
+        "restored": version_id,
+        "version": archived,
+        "edited_at": now,
+    })))
+}
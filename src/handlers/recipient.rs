@@ -0,0 +1,223 @@
+use std::{
+    io::{Read, Write},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use actix_web::{web, HttpResponse};
+use diesel::{pg::PgConnection, prelude::*};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::Pool;
+use crate::{errors::ServerError, schema::recipients::dsl::*};
+
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct Recipient {
+    pub handle: String,
+    pub public_key: String,
+    pub created_at: SystemTime,
+}
+
+#[derive(Deserialize)]
+pub struct NewRecipient {
+    handle: String,
+    public_key: String,
+}
+
+fn is_valid_handle(h: &str) -> bool {
+    (3..=64).contains(&h.len())
+        && h.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+pub async fn register(
+    input: web::Json<NewRecipient>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    if !is_valid_handle(&input.handle) {
+        return Ok(HttpResponse::BadRequest()
+            .body("handle must be 3 to 64 characters of letters, digits, '-', '_' or '.'"));
+    }
+
+    let key = input.public_key.trim();
+    if age::x25519::Recipient::from_str(key).is_err() {
+        return Ok(HttpResponse::BadRequest().body("public key is not a valid X25519 age key"));
+    }
+
+    let mut connection = pool.get()?;
+    match diesel::insert_into(recipients)
+        .values((handle.eq(&input.handle), public_key.eq(key)))
+        .get_result::<Recipient>(&mut connection)
+    {
+        Ok(recipient) => Ok(HttpResponse::Created().json(json!(recipient))),
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => Ok(HttpResponse::Conflict().body("handle has been taken")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn find(
+    recipient_handle: web::Path<String>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    let mut connection = pool.get()?;
+
+    match recipients
+        .find(recipient_handle.as_str())
+        .get_result::<Recipient>(&mut connection)
+    {
+        Ok(recipient) => Ok(HttpResponse::Ok().json(json!(recipient))),
+        Err(diesel::result::Error::NotFound) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Looks up the public keys registered under `handles`.
+/// Handles that aren't registered are left out.
+pub fn public_keys(
+    connection: &mut PgConnection,
+    handles: &[String],
+) -> Result<Vec<(String, String)>, ServerError> {
+    Ok(recipients
+        .select((handle, public_key))
+        .filter(handle.eq_any(handles))
+        .get_results::<(String, String)>(connection)?)
+}
+
+/// Handles of the recipients a note was sealed for
+pub fn sealed_for(connection: &mut PgConnection, nid: &str) -> Result<Vec<String>, ServerError> {
+    use crate::schema::note_recipients;
+
+    Ok(note_recipients::table
+        .select(note_recipients::recipient_handle)
+        .filter(note_recipients::note_id.eq(nid))
+        .order(note_recipients::recipient_handle.asc())
+        .get_results::<String>(connection)?)
+}
+
+/// Seals `plaintext` to every key as an ASCII-armored age file, which the
+/// recipients open locally with `age --decrypt --identity <their key>`.
+pub fn seal(keys: &[String], plaintext: &[u8]) -> Result<Vec<u8>, ServerError> {
+    let mut sealed = vec![];
+    seal_to(keys, plaintext, &mut sealed)?;
+    Ok(sealed)
+}
+
+/// Same as [`seal`], reading the plaintext and writing the age file as it goes
+pub fn seal_to(
+    keys: &[String],
+    mut plaintext: impl Read,
+    sealed: impl Write,
+) -> Result<(), ServerError> {
+    let keys = keys
+        .iter()
+        .map(|k| {
+            age::x25519::Recipient::from_str(k)
+                .map(|r| Box::new(r) as Box<dyn age::Recipient + Send>)
+                .map_err(|_| ServerError::AgeError)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let encryptor = age::Encryptor::with_recipients(keys).ok_or(ServerError::AgeError)?;
+
+    let armor = age::armor::ArmoredWriter::wrap_output(sealed, age::armor::Format::AsciiArmor)?;
+    let mut writer = encryptor.wrap_output(armor)?;
+    std::io::copy(&mut plaintext, &mut writer)?;
+    writer.finish()?.finish()?;
+
+    Ok(())
+}
+
+/// Opens an age file sealed by [`seal`] with the identity of one of its
+/// recipients.
+pub fn open(identity: &age::x25519::Identity, sealed: &[u8]) -> Result<Vec<u8>, ServerError> {
+    let decryptor = match age::Decryptor::new(age::armor::ArmoredReader::new(sealed))? {
+        age::Decryptor::Recipients(d) => d,
+        age::Decryptor::Passphrase(_) => return Err(ServerError::AgeError),
+    };
+
+    let mut plaintext = vec![];
+    decryptor
+        .decrypt(std::iter::once(identity as &dyn age::Identity))?
+        .read_to_end(&mut plaintext)?;
+
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+
+    fn key(identity: &age::x25519::Identity) -> String {
+        identity.to_public().to_string()
+    }
+
+    #[test]
+    fn seal_opens_for_every_recipient() {
+        let alice = age::x25519::Identity::generate();
+        let bob = age::x25519::Identity::generate();
+        let sealed = seal(&[key(&alice), key(&bob)], b"for both of you").unwrap();
+
+        assert_eq!(open(&alice, &sealed).unwrap(), b"for both of you");
+        assert_eq!(open(&bob, &sealed).unwrap(), b"for both of you");
+    }
+
+    #[test]
+    fn seal_writes_ascii_armor() {
+        let alice = age::x25519::Identity::generate();
+        let sealed = String::from_utf8(seal(&[key(&alice)], &[0, 159, 146, 150]).unwrap()).unwrap();
+
+        assert!(sealed.starts_with("-----BEGIN AGE ENCRYPTED FILE-----"));
+        assert!(sealed
+            .trim_end()
+            .ends_with("-----END AGE ENCRYPTED FILE-----"));
+    }
+
+    #[test]
+    fn seal_does_not_open_for_others() {
+        let alice = age::x25519::Identity::generate();
+        let eve = age::x25519::Identity::generate();
+        let sealed = seal(&[key(&alice)], b"not for eve").unwrap();
+
+        assert!(open(&eve, &sealed).is_err());
+    }
+
+    #[test]
+    fn seal_rejects_invalid_keys() {
+        assert!(matches!(seal(&[], b"nobody"), Err(ServerError::AgeError)));
+        assert!(matches!(
+            seal(&["age1notakey".to_string()], b"nobody"),
+            Err(ServerError::AgeError)
+        ));
+
+        // an identity isn't a recipient
+        let alice = age::x25519::Identity::generate();
+        let secret = alice.to_string().expose_secret().to_string();
+        assert!(matches!(
+            seal(&[secret], b"nobody"),
+            Err(ServerError::AgeError)
+        ));
+    }
+
+    #[test]
+    fn seal_to_streams_what_seal_seals() {
+        let alice = age::x25519::Identity::generate();
+        let plaintext = vec![42u8; 200_000];
+        let mut sealed = vec![];
+        seal_to(&[key(&alice)], &plaintext[..], &mut sealed).unwrap();
+
+        assert_eq!(open(&alice, &sealed).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn handles_are_limited() {
+        assert!(is_valid_handle("alice.smith-2_b"));
+        assert!(!is_valid_handle("al"));
+        assert!(!is_valid_handle(&"a".repeat(65)));
+        assert!(!is_valid_handle("alice smith"));
+        assert!(!is_valid_handle("alice@example.com"));
+    }
+}
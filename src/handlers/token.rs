@@ -7,7 +7,7 @@ use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey};
 use serde::Deserialize;
 use serde_json::json;
 
-use crate::schema::notes::dsl::*;
+use crate::schema::{dropboxes, notes::dsl::*};
 use diesel::prelude::*;
 
 #[derive(Clone, Deserialize)]
@@ -26,7 +26,7 @@ pub async fn verify(
     match decode::<Empty>(
         &body.token,
         &DecodingKey::from_secret(env.secret.as_ref()),
-        &validation,
+        validation,
     ) {
         Ok(_) => Ok(HttpResponse::Ok().finish()),
         Err(_) => Ok(HttpResponse::Unauthorized().finish()),
@@ -49,13 +49,13 @@ pub async fn combine(
     let secret = env.secret.as_ref();
     let original_token = decode::<Claims>(
         &body.first_token,
-        &DecodingKey::from_secret(&secret),
-        &validation,
+        &DecodingKey::from_secret(secret),
+        validation,
     )?;
     let mut second_token = decode::<Claims>(
         &body.second_token,
-        &DecodingKey::from_secret(&secret),
-        &validation,
+        &DecodingKey::from_secret(secret),
+        validation,
     )?;
     let mut token_data: Vec<(String, SystemTime)> = original_token
         .claims
@@ -64,11 +64,7 @@ pub async fn combine(
         .filter(|ot| {
             let res = second_token.claims.ids.iter().find(|&st| st.0 == ot.0);
             if let Some(st) = res {
-                if ot.1 > st.1 {
-                    true
-                } else {
-                    false
-                }
+                ot.1 > st.1
             } else {
                 true
             }
@@ -76,7 +72,7 @@ pub async fn combine(
         .collect();
     token_data.append(&mut second_token.claims.ids);
     let token = encode(
-        &header,
+        header,
         &Claims {
             iat: SystemTime::now(),
             ids: token_data,
@@ -86,7 +82,7 @@ pub async fn combine(
                 .unwrap_or("unknown")
                 .to_owned(),
         },
-        &EncodingKey::from_secret(secret.as_ref()),
+        &EncodingKey::from_secret(secret),
     )?;
     Ok(HttpResponse::Ok().json(json!({ "token": token })))
 }
@@ -102,7 +98,7 @@ pub async fn refresh_token(
     let validation = &env.jwt_validator;
     let header = &env.jwt_header;
     let secret: &[u8] = env.secret.as_ref();
-    let token = decode::<Claims>(&body.token, &DecodingKey::from_secret(&secret), &validation)?;
+    let token = decode::<Claims>(&body.token, &DecodingKey::from_secret(secret), validation)?;
 
     let mut result: Vec<(String, SystemTime)> = Vec::new();
     for _id in token.claims.ids.iter() {
@@ -111,15 +107,18 @@ pub async fn refresh_token(
             .select(created_at)
             .first::<SystemTime>(&mut connection)
         {
-            Ok(creation_time) => {
-                if creation_time == _id.1 {
-                    true
-                } else {
-                    false
-                }
-            }
+            Ok(creation_time) => creation_time == _id.1,
             Err(e) => match e {
-                diesel::result::Error::NotFound => false,
+                // ids that aren't notes may belong to drop boxes
+                diesel::result::Error::NotFound => match dropboxes::table
+                    .find(_id.0.to_owned())
+                    .select(dropboxes::created_at)
+                    .first::<SystemTime>(&mut connection)
+                {
+                    Ok(creation_time) => creation_time == _id.1,
+                    Err(diesel::result::Error::NotFound) => false,
+                    Err(_) => return Err(ServerError::DieselError),
+                },
                 _ => {
                     return Err(ServerError::DieselError);
                 }
@@ -132,7 +131,7 @@ pub async fn refresh_token(
     }
 
     let token = encode(
-        &header,
+        header,
         &Claims {
             iat: SystemTime::now(),
             ids: result,
@@ -142,7 +141,7 @@ pub async fn refresh_token(
                 .unwrap_or("unknown")
                 .to_owned(),
         },
-        &EncodingKey::from_secret(secret.as_ref()),
+        &EncodingKey::from_secret(secret),
     )?;
 
     Ok(HttpResponse::Ok().json(json!({ "token": token })))
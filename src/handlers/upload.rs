@@ -0,0 +1,505 @@
+//! Resumable uploads following the tus 1.0.0 protocol (creation, expiration
+//! and termination extensions), for notes too big to send in one request.
+//!
+//! The note is described in the `Upload-Metadata` of the creation request:
+//! - `note`: the same JSON as `POST /notes`, where `content` may be left out
+//! - `filename` and optionally `filetype`: the upload becomes an attachment of
+//!   the note instead of its content
+//!
+//! Metadata is kept as it was sent until the upload is over, so it can't hold
+//! passphrases. They go in the `X-Note-Passphrase` and
+//! `X-Note-Duress-Passphrase` headers of the request sending the last chunk.
+//!
+//! Chunks are staged as they arrive until the last one lands, which turns the
+//! upload into a note and answers like `POST /notes` would. An uploaded file is
+//! read back from where it was staged as it's sealed, the content of a note
+//! has to be put together first.
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Read},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{
+    http::header::{self, HttpDate},
+    web, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use diesel::prelude::*;
+use futures_util::StreamExt;
+use nanoid::nanoid;
+
+use super::{
+    note::{
+        attachment::{NewAttachment, NewContent},
+        mutate, query, JWTAuthQuery,
+    },
+    Pool,
+};
+use crate::{
+    errors::ServerError,
+    schema::{upload_chunks, uploads},
+    AppState,
+};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+const DURESS_PASSPHRASE_HEADER: &str = "X-Note-Duress-Passphrase";
+// a chunk is staged in pieces of this size as it arrives
+const STAGED_PIECE_SIZE: usize = 1024 * 1024;
+
+fn tus(mut response: HttpResponseBuilder) -> HttpResponseBuilder {
+    response.insert_header(("Tus-Resumable", TUS_VERSION));
+    response
+}
+
+/// Every request but `OPTIONS` has to say which version of tus it speaks
+fn unsupported_version(req: &HttpRequest) -> Option<HttpResponse> {
+    match req.headers().get("Tus-Resumable") {
+        Some(version) if version == TUS_VERSION => None,
+        _ => Some(
+            tus(HttpResponse::PreconditionFailed())
+                .insert_header(("Tus-Version", TUS_VERSION))
+                .finish(),
+        ),
+    }
+}
+
+fn numeric_header(req: &HttpRequest, name: &str) -> Option<i64> {
+    req.headers()
+        .get(name)?
+        .to_str()
+        .ok()?
+        .parse::<i64>()
+        .ok()
+        .filter(|n| *n >= 0)
+}
+
+/// Parses `Upload-Metadata`: comma separated pairs of a key and an optional
+/// base64 value
+fn parse_metadata(raw: &str) -> Option<HashMap<String, Vec<u8>>> {
+    let mut metadata = HashMap::new();
+
+    for pair in raw.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut parts = pair.splitn(2, ' ');
+        let key = parts.next()?;
+        let value = match parts.next() {
+            Some(value) => STANDARD.decode(value.trim()).ok()?,
+            None => vec![],
+        };
+        metadata.insert(key.to_owned(), value);
+    }
+
+    Some(metadata)
+}
+
+struct StagedNote {
+    note: mutate::NewNote,
+    file: Option<(String, String)>,
+}
+
+fn staged_note(raw: &str) -> Result<StagedNote, String> {
+    let metadata = parse_metadata(raw).ok_or("Upload-Metadata is malformed")?;
+
+    let note = match metadata.get("note") {
+        Some(note) => serde_json::from_slice::<mutate::NewNote>(note)
+            .map_err(|e| format!("note metadata: {e}"))?,
+        None => return Err("note metadata is missing".to_string()),
+    };
+    if note.passphrase.is_some() || note.duress_passphrase.is_some() {
+        return Err(format!(
+            "passphrases can't be sent in Upload-Metadata, send them in {} and {DURESS_PASSPHRASE_HEADER} along with the last chunk",
+            query::PASSPHRASE_HEADER
+        ));
+    }
+
+    let text = |key: &str| {
+        metadata
+            .get(key)
+            .map(|v| String::from_utf8(v.to_owned()).map_err(|_| format!("{key} is not UTF-8")))
+            .transpose()
+    };
+
+    let file = match text("filename")? {
+        Some(name) if name.trim().is_empty() || name.len() > 255 => {
+            return Err("filename must be at most 255 bytes".to_string());
+        }
+        Some(name) => Some((
+            name,
+            text("filetype")?.unwrap_or("application/octet-stream".to_string()),
+        )),
+        None => None,
+    };
+
+    Ok(StagedNote { note, file })
+}
+
+pub async fn options(env: web::Data<AppState>) -> HttpResponse {
+    tus(HttpResponse::NoContent())
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", env.max_upload_size.to_string()))
+        .finish()
+}
+
+pub async fn new(
+    req: HttpRequest,
+    env: web::Data<AppState>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    if let Some(response) = unsupported_version(&req) {
+        return Ok(response);
+    }
+
+    let length = match numeric_header(&req, "Upload-Length") {
+        Some(length) => length,
+        None => return Ok(tus(HttpResponse::BadRequest()).body("Upload-Length is missing")),
+    };
+    if length as u64 > env.max_upload_size {
+        return Ok(tus(HttpResponse::PayloadTooLarge()).body(format!(
+            "uploads are limited to {} bytes",
+            env.max_upload_size
+        )));
+    }
+
+    let metadata = req
+        .headers()
+        .get("Upload-Metadata")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    // catch a bad note before its content is sent, not after
+    let staged = match staged_note(&metadata) {
+        Ok(staged) => staged,
+        Err(reason) => return Ok(tus(HttpResponse::BadRequest()).body(reason)),
+    };
+    if staged.file.is_some() && length as u64 > env.max_attachment_size as u64 {
+        return Ok(tus(HttpResponse::PayloadTooLarge()).body(format!(
+            "attachments are limited to {} bytes",
+            env.max_attachment_size
+        )));
+    }
+
+    let mut connection = pool.get()?;
+    let expiry_time = SystemTime::now() + Duration::from_secs(env.upload_expiry);
+    let upload_id = diesel::insert_into(uploads::table)
+        .values((
+            uploads::id.eq(nanoid!()),
+            uploads::upload_length.eq(length),
+            uploads::metadata.eq(metadata),
+            uploads::expires_at.eq(expiry_time),
+        ))
+        .returning(uploads::id)
+        .get_result::<String>(&mut connection)?;
+
+    Ok(tus(HttpResponse::Created())
+        .insert_header((header::LOCATION, format!("/uploads/{upload_id}")))
+        .insert_header(("Upload-Expires", HttpDate::from(expiry_time).to_string()))
+        .finish())
+}
+
+#[derive(Queryable)]
+struct Upload {
+    upload_length: i64,
+    upload_offset: i64,
+    metadata: Option<String>,
+    expires_at: SystemTime,
+}
+
+fn find_upload(
+    connection: &mut PgConnection,
+    upload_id: &str,
+) -> Result<Option<Upload>, ServerError> {
+    match uploads::table
+        .select((
+            uploads::upload_length,
+            uploads::upload_offset,
+            uploads::metadata,
+            uploads::expires_at,
+        ))
+        .find(upload_id)
+        .get_result::<Upload>(connection)
+    {
+        Ok(upload) if upload.expires_at > SystemTime::now() => Ok(Some(upload)),
+        Ok(_) | Err(diesel::result::Error::NotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn offset(
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    if let Some(response) = unsupported_version(&req) {
+        return Ok(response);
+    }
+
+    let mut connection = pool.get()?;
+    match find_upload(&mut connection, &upload_id)? {
+        Some(upload) => Ok(tus(HttpResponse::Ok())
+            .insert_header(("Upload-Offset", upload.upload_offset.to_string()))
+            .insert_header(("Upload-Length", upload.upload_length.to_string()))
+            .insert_header((
+                "Upload-Expires",
+                HttpDate::from(upload.expires_at).to_string(),
+            ))
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .finish()),
+        None => Ok(tus(HttpResponse::NotFound()).finish()),
+    }
+}
+
+/// Stages a piece of the upload at `offset`, returning whether the offset was
+/// still there to move, which it isn't if another request moved it meanwhile
+fn stage(
+    connection: &mut PgConnection,
+    upload_id: &str,
+    offset: i64,
+    piece: &[u8],
+) -> Result<bool, ServerError> {
+    Ok(
+        connection.transaction::<_, diesel::result::Error, _>(|connection| {
+            let moved = diesel::update(
+                uploads::table
+                    .filter(uploads::id.eq(upload_id))
+                    .filter(uploads::upload_offset.eq(offset)),
+            )
+            .set(uploads::upload_offset.eq(offset + piece.len() as i64))
+            .execute(connection)?;
+
+            if moved == 1 {
+                diesel::insert_into(upload_chunks::table)
+                    .values((
+                        upload_chunks::upload_id.eq(upload_id),
+                        upload_chunks::chunk_offset.eq(offset),
+                        upload_chunks::data.eq(piece),
+                    ))
+                    .execute(connection)?;
+            }
+
+            Ok(moved == 1)
+        })?,
+    )
+}
+
+/// Reads the pieces staged for an upload back in order, one at a time
+struct StagedReader {
+    pool: Pool,
+    upload_id: String,
+    offsets: std::vec::IntoIter<i64>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl StagedReader {
+    fn new(
+        connection: &mut PgConnection,
+        pool: Pool,
+        upload_id: &str,
+    ) -> Result<Self, ServerError> {
+        let offsets = upload_chunks::table
+            .select(upload_chunks::chunk_offset)
+            .filter(upload_chunks::upload_id.eq(upload_id))
+            .order(upload_chunks::chunk_offset.asc())
+            .get_results::<i64>(connection)?;
+
+        Ok(Self {
+            pool,
+            upload_id: upload_id.to_owned(),
+            offsets: offsets.into_iter(),
+            current: Cursor::new(vec![]),
+        })
+    }
+
+    fn piece(&self, offset: i64) -> Result<Vec<u8>, ServerError> {
+        Ok(upload_chunks::table
+            .select(upload_chunks::data)
+            .filter(upload_chunks::upload_id.eq(&self.upload_id))
+            .filter(upload_chunks::chunk_offset.eq(offset))
+            .get_result::<Vec<u8>>(&mut self.pool.get()?)?)
+    }
+}
+
+impl Read for StagedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match self.offsets.next() {
+                Some(offset) => {
+                    let piece = self
+                        .piece(offset)
+                        .map_err(|e| io::Error::other(format!("staged piece is gone: {e}")))?;
+                    self.current = Cursor::new(piece);
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+pub async fn append(
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    mut payload: web::Payload,
+    auth: web::Query<JWTAuthQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    if let Some(response) = unsupported_version(&req) {
+        return Ok(response);
+    }
+
+    if req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|v| v.as_bytes())
+        != Some(OFFSET_CONTENT_TYPE.as_bytes())
+    {
+        return Ok(tus(HttpResponse::UnsupportedMediaType())
+            .body(format!("Content-Type must be {OFFSET_CONTENT_TYPE}")));
+    }
+
+    let sent_offset = match numeric_header(&req, "Upload-Offset") {
+        Some(sent_offset) => sent_offset,
+        None => return Ok(tus(HttpResponse::BadRequest()).body("Upload-Offset is missing")),
+    };
+
+    let mut connection = pool.get()?;
+    let upload = match find_upload(&mut connection, &upload_id)? {
+        Some(upload) => upload,
+        None => return Ok(tus(HttpResponse::NotFound()).finish()),
+    };
+
+    if sent_offset != upload.upload_offset {
+        return Ok(tus(HttpResponse::Conflict())
+            .insert_header(("Upload-Offset", upload.upload_offset.to_string()))
+            .body("Upload-Offset does not match the offset of the upload"));
+    }
+
+    // staged a piece at a time, so a big chunk never sits in memory whole and
+    // whatever arrived before a dropped connection is kept
+    let mut new_offset = upload.upload_offset;
+    let mut piece: Vec<u8> = vec![];
+    loop {
+        let bytes = payload.next().await.transpose().map_err(|e| {
+            log::error!("Failed to receive upload chunk: {e}");
+            ServerError::IOError
+        })?;
+        if let Some(bytes) = &bytes {
+            if new_offset + (piece.len() + bytes.len()) as i64 > upload.upload_length {
+                return Ok(tus(HttpResponse::PayloadTooLarge())
+                    .body("chunk goes past the Upload-Length of the upload"));
+            }
+            piece.extend_from_slice(bytes);
+        }
+
+        if piece.len() >= STAGED_PIECE_SIZE || (bytes.is_none() && !piece.is_empty()) {
+            if !stage(&mut connection, &upload_id, new_offset, &piece)? {
+                return Ok(tus(HttpResponse::Conflict())
+                    .body("upload was moved by another request, check its offset"));
+            }
+            new_offset += piece.len() as i64;
+            piece.clear();
+        }
+        if bytes.is_none() {
+            break;
+        }
+    }
+
+    if new_offset < upload.upload_length {
+        return Ok(tus(HttpResponse::NoContent())
+            .insert_header(("Upload-Offset", new_offset.to_string()))
+            .insert_header((
+                "Upload-Expires",
+                HttpDate::from(upload.expires_at).to_string(),
+            ))
+            .finish());
+    }
+
+    let staged = match staged_note(upload.metadata.as_deref().unwrap_or_default()) {
+        Ok(staged) => staged,
+        Err(reason) => return Ok(tus(HttpResponse::BadRequest()).body(reason)),
+    };
+
+    let mut note = staged.note;
+    note.passphrase = match query::passphrase_from_header(&req) {
+        Ok(passphrase) => passphrase,
+        Err(reason) => return Ok(tus(HttpResponse::BadRequest()).body(reason)),
+    };
+    note.duress_passphrase = match req.headers().get(DURESS_PASSPHRASE_HEADER) {
+        Some(value) => match value.to_str() {
+            Ok(passphrase) => Some(passphrase.to_owned()),
+            Err(_) => {
+                return Ok(tus(HttpResponse::BadRequest())
+                    .body(format!("{DURESS_PASSPHRASE_HEADER} must be visible ASCII")));
+            }
+        },
+        None => None,
+    };
+    let files = match staged.file {
+        Some((name, mime_type)) => vec![NewAttachment {
+            name,
+            mime_type,
+            content: NewContent::Reader(
+                Box::new(StagedReader::new(
+                    &mut connection,
+                    pool.get_ref().clone(),
+                    &upload_id,
+                )?),
+                new_offset as usize,
+            ),
+        }],
+        None => match String::from_utf8(
+            upload_chunks::table
+                .select(upload_chunks::data)
+                .filter(upload_chunks::upload_id.eq(upload_id.as_str()))
+                .order(upload_chunks::chunk_offset.asc())
+                .get_results::<Vec<u8>>(&mut connection)?
+                .concat(),
+        ) {
+            Ok(text) => {
+                note.content = text;
+                vec![]
+            }
+            Err(_) => {
+                return Ok(tus(HttpResponse::BadRequest())
+                    .body("note content is not UTF-8, upload it with a filename instead"));
+            }
+        },
+    };
+
+    let response = mutate::create(req, note, files, auth.into_inner(), pool.clone()).await?;
+    // the upload is over either way, a rejected note has to be uploaded again
+    diesel::delete(uploads::table.find(upload_id.as_str())).execute(&mut connection)?;
+
+    let (mut response, body) = response.into_parts();
+    response.headers_mut().insert(
+        header::HeaderName::from_static("tus-resumable"),
+        header::HeaderValue::from_static(TUS_VERSION),
+    );
+    response.headers_mut().insert(
+        header::HeaderName::from_static("upload-offset"),
+        header::HeaderValue::from(new_offset),
+    );
+    Ok(response.set_body(body))
+}
+
+pub async fn del(
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, ServerError> {
+    if let Some(response) = unsupported_version(&req) {
+        return Ok(response);
+    }
+
+    let mut connection = pool.get()?;
+    match diesel::delete(uploads::table.find(upload_id.as_str())).execute(&mut connection)? {
+        0 => Ok(tus(HttpResponse::NotFound()).finish()),
+        _ => Ok(tus(HttpResponse::NoContent()).finish()),
+    }
+}
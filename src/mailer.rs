@@ -0,0 +1,467 @@
+//! Emails to the owner of a note when it's read for the first time, or when
+//! it expires without ever being read.
+//!
+//! Off unless `SMTP_HOST` is set. Emails are queued in `email_notifications`
+//! and sent by a worker thread the same way webhook receipts are. The address
+//! given for a note is only kept sealed, under a key derived from
+//! `SECRET_KEY`, and is opened just before an email goes out.
+use std::{
+    sync::{mpsc, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::http::header::HttpDate;
+use diesel::{pg::PgConnection, prelude::*};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    Address, Message, SmtpTransport, Transport,
+};
+use ring::digest;
+use tindercrypt::cryptors::RingCryptor;
+
+use crate::{
+    errors::ServerError,
+    handlers::Pool,
+    schema::{email_notifications, notes},
+    webhook,
+};
+
+const MAX_ADDRESS_LENGTH: usize = 254;
+const MAX_ATTEMPTS: i32 = 5;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: i64 = 20;
+// how much longer than sending may take a claimed email is left to the
+// worker that claimed it, before another one may try it again
+const CLAIM_MARGIN: Duration = Duration::from_secs(60);
+const KEEP_FINISHED: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// keeps the key sealing addresses apart from anything else SECRET_KEY is for
+const KEY_CONTEXT: &[u8] = b"himitsu notify_email";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Security {
+    None,
+    StartTls,
+    Tls,
+}
+
+struct Settings {
+    host: String,
+    port: u16,
+    security: Security,
+    credentials: Option<Credentials>,
+    from: Mailbox,
+    timeout: Duration,
+}
+
+static SETTINGS: OnceLock<Option<Settings>> = OnceLock::new();
+static ADDRESS_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+static WAKE: OnceLock<mpsc::Sender<()>> = OnceLock::new();
+
+fn settings() -> Option<&'static Settings> {
+    SETTINGS
+        .get_or_init(|| {
+            let host = std::env::var("SMTP_HOST").ok()?;
+            let security = match std::env::var("SMTP_TLS").as_deref() {
+                Ok("none") => Security::None,
+                Ok("starttls") | Err(_) => Security::StartTls,
+                Ok("tls") => Security::Tls,
+                Ok(other) => panic!("SMTP_TLS must be none, starttls or tls, not {other}"),
+            };
+            Some(Settings {
+                host,
+                port: std::env::var("SMTP_PORT")
+                    .unwrap_or(
+                        if security == Security::Tls {
+                            "465"
+                        } else {
+                            "587"
+                        }
+                        .to_string(),
+                    )
+                    .parse::<u16>()
+                    .expect("must be a port number"),
+                security,
+                credentials: match (
+                    std::env::var("SMTP_USERNAME"),
+                    std::env::var("SMTP_PASSWORD"),
+                ) {
+                    (Ok(username), Ok(password)) => Some(Credentials::new(username, password)),
+                    _ => None,
+                },
+                from: std::env::var("SMTP_FROM")
+                    .expect("SMTP_FROM is needed along with SMTP_HOST")
+                    .parse::<Mailbox>()
+                    .expect("SMTP_FROM must be an email address"),
+                timeout: Duration::from_secs(
+                    std::env::var("SMTP_TIMEOUT")
+                        .unwrap_or("10".to_string())
+                        .parse::<u64>()
+                        .expect("must be an unsigned number of seconds"),
+                ),
+            })
+        })
+        .as_ref()
+}
+
+pub fn enabled() -> bool {
+    settings().is_some()
+}
+
+fn address_key() -> &'static [u8] {
+    ADDRESS_KEY.get_or_init(|| {
+        let secret = std::env::var("SECRET_KEY").expect("SECRET_KEY in .env");
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(KEY_CONTEXT);
+        context.update(secret.as_bytes());
+        context.finish().as_ref().to_vec()
+    })
+}
+
+/// Checks a notification address given for a note, telling what's wrong
+/// with it
+pub fn validate_address(raw: &str) -> Result<(), String> {
+    if raw.len() > MAX_ADDRESS_LENGTH {
+        return Err(format!(
+            "notify email is longer than {MAX_ADDRESS_LENGTH} characters"
+        ));
+    }
+    raw.parse::<Address>()
+        .map(|_| ())
+        .map_err(|e| format!("notify email is not valid: {e}"))
+}
+
+pub fn seal_address(address: &str) -> Result<Vec<u8>, ServerError> {
+    Ok(RingCryptor::new().seal_with_key(address_key(), address.as_bytes())?)
+}
+
+fn open_address(sealed: &[u8]) -> Result<Mailbox, String> {
+    let opened = RingCryptor::new()
+        .open(address_key(), sealed)
+        .map_err(|e| format!("address can't be opened: {e}"))?;
+    String::from_utf8(opened)
+        .map_err(|e| e.to_string())?
+        .parse::<Mailbox>()
+        .map_err(|e| e.to_string())
+}
+
+fn describe(nid: &str, title: Option<&str>) -> String {
+    match title {
+        Some(title) => format!("Your note \"{title}\" ({nid})"),
+        None => format!("Your note {nid}"),
+    }
+}
+
+fn enqueue(
+    connection: &mut PgConnection,
+    nid: &str,
+    title: Option<&str>,
+    recipient: Option<Vec<u8>>,
+    subject: impl Fn(&str) -> String,
+    body: impl Fn(&str) -> String,
+) -> Result<(), ServerError> {
+    let recipient = match recipient {
+        Some(recipient) if enabled() => recipient,
+        _ => return Ok(()),
+    };
+
+    let note = describe(nid, title);
+    diesel::insert_into(email_notifications::table)
+        .values((
+            email_notifications::note_id.eq(nid),
+            email_notifications::recipient.eq(recipient),
+            email_notifications::subject.eq(subject(&note)),
+            email_notifications::body.eq(body(&note)),
+        ))
+        .execute(connection)?;
+    Ok(())
+}
+
+/// Gets the worker to queued emails right away instead of on its next round
+pub fn wake() {
+    if let Some(wake) = WAKE.get() {
+        wake.send(()).ok();
+    }
+}
+
+/// Queues an email for the first read of the note, if it has an address
+pub fn enqueue_first_read(connection: &mut PgConnection, nid: &str) -> Result<(), ServerError> {
+    let (title, recipient) = notes::table
+        .select((notes::title, notes::notify_email))
+        .find(nid)
+        .first::<(Option<String>, Option<Vec<u8>>)>(connection)?;
+
+    let at = HttpDate::from(SystemTime::now());
+    enqueue(
+        connection,
+        nid,
+        title.as_deref(),
+        recipient,
+        |note| format!("{note} was read"),
+        |note| format!("{note} was read for the first time on {at}.\n"),
+    )?;
+    wake();
+    Ok(())
+}
+
+/// Queues an email for a note that expired without ever being read, if it
+/// has an address. Meant for the transaction deleting the note, with what
+/// the delete returned, so the email goes out only if the note is gone.
+pub fn enqueue_expired_unread(
+    connection: &mut PgConnection,
+    nid: &str,
+    title: Option<&str>,
+    recipient: Option<Vec<u8>>,
+) -> Result<(), ServerError> {
+    enqueue(
+        connection,
+        nid,
+        title,
+        recipient,
+        |note| format!("{note} expired unread"),
+        |note| format!("{note} expired without ever being read, and has been deleted.\n"),
+    )
+}
+
+#[derive(Queryable)]
+struct Notification {
+    id: i32,
+    recipient: Vec<u8>,
+    subject: String,
+    body: String,
+    attempts: i32,
+}
+
+fn send(
+    transport: &SmtpTransport,
+    settings: &Settings,
+    notification: &Notification,
+) -> Result<(), String> {
+    let message = Message::builder()
+        .from(settings.from.clone())
+        .to(open_address(&notification.recipient)?)
+        .subject(&notification.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(notification.body.to_owned())
+        .map_err(|e| e.to_string())?;
+    transport.send(&message).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Takes the next email that's due, the way webhook receipts are claimed,
+/// so workers on other replicas leave it alone while it's sent
+fn claim_next(
+    connection: &mut PgConnection,
+    settings: &Settings,
+) -> Result<Option<Notification>, ServerError> {
+    connection.transaction(|connection| {
+        let notification = email_notifications::table
+            .select((
+                email_notifications::id,
+                email_notifications::recipient,
+                email_notifications::subject,
+                email_notifications::body,
+                email_notifications::attempts,
+            ))
+            .filter(email_notifications::sent_at.is_null())
+            .filter(email_notifications::failed_at.is_null())
+            .filter(email_notifications::next_attempt_at.le(SystemTime::now()))
+            .order(email_notifications::next_attempt_at.asc())
+            .for_update()
+            .skip_locked()
+            .first::<Notification>(connection)
+            .optional()?;
+
+        if let Some(notification) = &notification {
+            diesel::update(email_notifications::table.find(notification.id))
+                .set(
+                    email_notifications::next_attempt_at
+                        .eq(SystemTime::now() + settings.timeout + CLAIM_MARGIN),
+                )
+                .execute(connection)?;
+        }
+        Ok(notification)
+    })
+}
+
+/// Sends every email that's due, up to a batch, returning how many went out
+fn send_due(
+    connection: &mut PgConnection,
+    transport: &SmtpTransport,
+    settings: &Settings,
+) -> Result<usize, ServerError> {
+    let mut sent = 0;
+    for _ in 0..BATCH_SIZE {
+        let notification = match claim_next(connection, settings)? {
+            Some(notification) => notification,
+            None => break,
+        };
+        let attempts = notification.attempts + 1;
+        let now = SystemTime::now();
+        let row = email_notifications::table.find(notification.id);
+
+        match send(transport, settings, &notification) {
+            Ok(()) => {
+                diesel::update(row)
+                    .set((
+                        email_notifications::attempts.eq(attempts),
+                        email_notifications::sent_at.eq(now),
+                    ))
+                    .execute(connection)?;
+                sent += 1;
+            }
+            Err(reason) if attempts >= MAX_ATTEMPTS => {
+                log::warn!(
+                    "Giving up on email notification {} after {attempts} attempts: {reason}",
+                    notification.id
+                );
+                diesel::update(row)
+                    .set((
+                        email_notifications::attempts.eq(attempts),
+                        email_notifications::failed_at.eq(now),
+                    ))
+                    .execute(connection)?;
+            }
+            Err(reason) => {
+                log::info!(
+                    "Email notification {} failed, retrying: {reason}",
+                    notification.id
+                );
+                diesel::update(row)
+                    .set((
+                        email_notifications::attempts.eq(attempts),
+                        email_notifications::next_attempt_at.eq(now + webhook::backoff(attempts)),
+                    ))
+                    .execute(connection)?;
+            }
+        }
+    }
+
+    Ok(sent)
+}
+
+fn transport(settings: &Settings) -> SmtpTransport {
+    let mut builder = match settings.security {
+        Security::None => SmtpTransport::builder_dangerous(&settings.host),
+        Security::StartTls => {
+            SmtpTransport::starttls_relay(&settings.host).expect("SMTP_HOST must be a host name")
+        }
+        Security::Tls => {
+            SmtpTransport::relay(&settings.host).expect("SMTP_HOST must be a host name")
+        }
+    }
+    .port(settings.port)
+    .timeout(Some(settings.timeout));
+    if let Some(credentials) = &settings.credentials {
+        builder = builder.credentials(credentials.clone());
+    }
+    builder.build()
+}
+
+/// Starts the thread sending emails, when SMTP is set up
+pub fn spawn_worker(pool: Pool) {
+    let settings = match settings() {
+        Some(settings) => settings,
+        None => return,
+    };
+    let (wake, woken) = mpsc::channel();
+    WAKE.set(wake).ok();
+
+    let transport = transport(settings);
+    std::thread::spawn(move || loop {
+        match pool.get() {
+            Ok(mut connection) => match send_due(&mut connection, &transport, settings) {
+                Ok(0) => (),
+                Ok(sent) => log::info!("Sent {sent} email notifications"),
+                Err(e) => log::error!("Failed to send email notifications: {e}"),
+            },
+            Err(e) => log::error!("Failed to send email notifications: {e}"),
+        }
+
+        woken.recv_timeout(POLL_INTERVAL).ok();
+        while woken.try_recv().is_ok() {}
+    });
+}
+
+/// Deletes emails that went out or were given up on a while ago, returning
+/// how many were deleted
+pub fn remove_finished(connection: &mut PgConnection) -> Result<usize, ServerError> {
+    let cutoff = SystemTime::now() - KEEP_FINISHED;
+    Ok(diesel::delete(
+        email_notifications::table.filter(
+            email_notifications::sent_at
+                .le(cutoff)
+                .or(email_notifications::failed_at.le(cutoff)),
+        ),
+    )
+    .execute(connection)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_address_takes_plain_addresses() {
+        assert!(validate_address("owner@example.com").is_ok());
+    }
+
+    #[test]
+    fn validate_address_refuses_malformed_addresses() {
+        for raw in [
+            "",
+            "owner",
+            "owner@",
+            "@example.com",
+            "Owner <owner@example.com>",
+        ] {
+            assert!(validate_address(raw).is_err(), "{raw} is not an address");
+        }
+    }
+
+    #[test]
+    fn validate_address_refuses_long_addresses() {
+        let raw = format!("{}@example.com", "a".repeat(MAX_ADDRESS_LENGTH));
+        assert!(validate_address(&raw).is_err());
+    }
+
+    #[test]
+    fn describe_names_the_title_when_there_is_one() {
+        assert_eq!(
+            describe("abc123", Some("Keys")),
+            "Your note \"Keys\" (abc123)"
+        );
+        assert_eq!(describe("abc123", None), "Your note abc123");
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL, SECRET_KEY and an SMTP server such as MailHog in SMTP_HOST, SMTP_PORT, SMTP_TLS and SMTP_FROM"]
+    fn send_due_sends_queued_emails() {
+        let settings = settings().expect("SMTP_HOST is set");
+        let mut connection =
+            PgConnection::establish(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+
+        let notification = diesel::insert_into(email_notifications::table)
+            .values((
+                email_notifications::note_id.eq("mailer-test"),
+                email_notifications::recipient.eq(seal_address("owner@example.com").unwrap()),
+                email_notifications::subject.eq("Your note mailer-test was read"),
+                email_notifications::body.eq("Your note mailer-test was read.\n"),
+            ))
+            .returning(email_notifications::id)
+            .get_result::<i32>(&mut connection)
+            .unwrap();
+
+        send_due(&mut connection, &transport(settings), settings).unwrap();
+
+        let (attempts, sent) = email_notifications::table
+            .select((
+                email_notifications::attempts,
+                email_notifications::sent_at.is_not_null(),
+            ))
+            .find(notification)
+            .first::<(i32, bool)>(&mut connection)
+            .unwrap();
+        assert_eq!((attempts, sent), (1, true));
+    }
+}
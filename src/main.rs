@@ -2,7 +2,10 @@ use std::time::SystemTime;
 
 use actix_cors::Cors;
 use actix_governor::{Governor, GovernorConfigBuilder};
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_web::{
+    middleware::{Condition, Logger},
+    web, App, HttpServer,
+};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
@@ -12,9 +15,15 @@ use jsonwebtoken::{Algorithm, Header, Validation};
 #[macro_use]
 extern crate diesel;
 
+mod blob;
 mod errors;
+mod events;
 mod handlers;
+mod mailer;
+mod rate_limit;
+mod redis_pool;
 mod schema;
+mod webhook;
 
 const MIGRATION: EmbeddedMigrations = embed_migrations!();
 
@@ -28,22 +37,72 @@ async fn main() -> std::io::Result<()> {
         ))
         .expect("Failed to create a pool");
 
+    blob::init();
+    redis_pool::init();
     let mut connection = pool.get().unwrap();
     MigrationHarness::run_pending_migrations(&mut pool.get().unwrap(), MIGRATION)
         .expect("migration run failed, please check your database configuration!");
+    webhook::spawn_worker(pool.clone());
+    mailer::spawn_worker(pool.clone());
+    events::spawn_listener(env.db_url.to_owned());
     std::thread::spawn(move || loop {
-        use schema::notes::dsl::notes;
         log::info!("Clearing invalid notes in database!");
-        diesel::delete(
-            notes
-                .filter(schema::notes::expires_at.le(SystemTime::now()))
-                .or_filter(schema::notes::delete_after_read.eq(0)),
+        match handlers::note::versions::remove_expired(&mut connection) {
+            Ok(0) => (),
+            Ok(removed) => log::info!("Removed {removed} expired note versions"),
+            Err(e) => log::error!("Failed to remove expired note versions: {e}"),
+        }
+        match handlers::note::query::remove_gone(&mut connection, None) {
+            Ok(0) => (),
+            Ok(removed) => log::info!("Removed {removed} expired or read out notes"),
+            Err(e) => log::error!("Failed to remove expired or read out notes: {e}"),
+        }
+        match diesel::delete(
+            schema::dropboxes::table
+                .filter(schema::dropboxes::expires_at.le(SystemTime::now()))
+                .or_filter(schema::dropboxes::delete_after_read.eq(0)),
         )
         .execute(&mut connection)
-        .unwrap();
+        {
+            Ok(0) => (),
+            Ok(removed) => log::info!("Removed {removed} expired or used up dropboxes"),
+            Err(e) => log::error!("Failed to remove expired dropboxes: {e}"),
+        }
+        match diesel::delete(
+            schema::uploads::table.filter(schema::uploads::expires_at.le(SystemTime::now())),
+        )
+        .execute(&mut connection)
+        {
+            Ok(0) => (),
+            Ok(removed) => log::info!("Removed {removed} abandoned uploads"),
+            Err(e) => log::error!("Failed to remove abandoned uploads: {e}"),
+        }
+        match webhook::remove_finished(&mut connection) {
+            Ok(0) => (),
+            Ok(removed) => log::info!("Removed {removed} finished webhook deliveries"),
+            Err(e) => log::error!("Failed to remove finished webhook deliveries: {e}"),
+        }
+        match mailer::remove_finished(&mut connection) {
+            Ok(0) => (),
+            Ok(removed) => log::info!("Removed {removed} finished email notifications"),
+            Err(e) => log::error!("Failed to remove finished email notifications: {e}"),
+        }
+        match blob::remove_orphans(&mut connection) {
+            Ok(0) => (),
+            Ok(removed) => log::info!("Removed {removed} orphaned blobs"),
+            Err(e) => log::error!("Failed to remove orphaned blobs: {e}"),
+        }
         std::thread::sleep(std::time::Duration::from_secs(env.cleanup_interval));
     });
 
+    // built once so every worker counts against the same limits
+    let governor = GovernorConfigBuilder::default()
+        .per_millisecond(rate_limit::PERIOD.as_millis() as u64)
+        .burst_size(rate_limit::BURST)
+        .finish()
+        .unwrap();
+    let shared_rate_limit = rate_limit::shared();
+
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(env.clone()))
@@ -54,14 +113,13 @@ async fn main() -> std::io::Result<()> {
                     .allow_any_origin()
                     .allow_any_method()
                     .allow_any_header()
+                    .expose_any_header()
                     .max_age(3600),
             )
-            .wrap(Governor::new(
-                &GovernorConfigBuilder::default()
-                    .per_millisecond(1500)
-                    .burst_size(3)
-                    .finish()
-                    .unwrap(),
+            .wrap(Condition::new(!shared_rate_limit, Governor::new(&governor)))
+            .wrap(Condition::new(
+                shared_rate_limit,
+                rate_limit::RedisRateLimit,
             ))
             .wrap(Logger::default())
             .configure(handlers::config)
@@ -76,6 +134,10 @@ pub struct AppState {
     pub secret: String,
     pub jwt_validator: Validation,
     pub jwt_header: Header,
+    pub max_attachment_size: usize,
+    pub max_attachments: usize,
+    pub max_upload_size: u64,
+    pub upload_expiry: u64,
     db_url: String,
     app_address: String,
     cleanup_interval: u64,
@@ -95,6 +157,22 @@ impl AppState {
                 .unwrap_or("2700".to_string())
                 .parse::<u64>()
                 .expect("must be an unsigned 64-bit number"),
+            max_attachment_size: std::env::var("MAX_ATTACHMENT_SIZE")
+                .unwrap_or("10485760".to_string())
+                .parse::<usize>()
+                .expect("must be an unsigned number of bytes"),
+            max_attachments: std::env::var("MAX_ATTACHMENTS")
+                .unwrap_or("5".to_string())
+                .parse::<usize>()
+                .expect("must be an unsigned number"),
+            max_upload_size: std::env::var("MAX_UPLOAD_SIZE")
+                .unwrap_or("268435456".to_string())
+                .parse::<u64>()
+                .expect("must be an unsigned number of bytes"),
+            upload_expiry: std::env::var("UPLOAD_EXPIRY")
+                .unwrap_or("86400".to_string())
+                .parse::<u64>()
+                .expect("must be an unsigned 64-bit number"),
             jwt_validator: validation,
             jwt_header: Header::new(Algorithm::HS512),
             secret: std::env::var("SECRET_KEY").expect("SECRET_KEY in .env"),
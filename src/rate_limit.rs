@@ -0,0 +1,200 @@
+//! Rate limiting per client address.
+//!
+//! A single instance counts requests in memory with `Governor`. With
+//! `REDIS_URL` set the counts are kept in Redis instead, so every replica
+//! behind a load balancer draws from the same allowance. Redis counts in fixed
+//! windows of `BURST` requests every `BURST * PERIOD`, which allows the same
+//! rate as `Governor` does.
+//!
+//! When Redis can't be reached requests are let through, so an outage there
+//! doesn't take the whole service down with it.
+use std::{net::IpAddr, rc::Rc, time::Duration};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::ContentType,
+    web, Error, HttpResponse,
+};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+
+use crate::redis_pool;
+
+/// How often a client earns another request
+pub const PERIOD: Duration = Duration::from_millis(1500);
+/// Requests a client can make at once
+pub const BURST: u32 = 3;
+
+const KEY_PREFIX: &str = "himitsu:ratelimit:";
+
+/// Whether limits are shared through Redis
+pub fn shared() -> bool {
+    redis_pool::pool().is_some()
+}
+
+/// How long a window of `BURST` requests lasts, in milliseconds
+fn window() -> u64 {
+    PERIOD.as_millis() as u64 * BURST as u64
+}
+
+/// How many milliseconds a client has to wait after its `requests`th request
+/// of a window with `left` milliseconds to go, if it's over its limit
+fn over_limit(requests: u64, left: i64) -> Option<u64> {
+    (requests > BURST as u64).then_some(left.max(0) as u64)
+}
+
+/// Counts a request from `ip`, returning how many milliseconds are left
+/// until it may make another if it's over its limit
+fn count(ip: IpAddr) -> Result<Option<u64>, String> {
+    let pool = redis_pool::pool().ok_or("redis is not set up")?;
+    let mut connection = pool.get().map_err(|e| e.to_string())?;
+
+    let key = format!("{KEY_PREFIX}{ip}");
+    // the first request of a window starts it, no clocks need to agree
+    let (requests, left) = redis::pipe()
+        .atomic()
+        .cmd("SET")
+        .arg(&key)
+        .arg(0)
+        .arg("PX")
+        .arg(window())
+        .arg("NX")
+        .ignore()
+        .cmd("INCR")
+        .arg(&key)
+        .cmd("PTTL")
+        .arg(&key)
+        .query::<(u64, i64)>(&mut *connection)
+        .map_err(|e| e.to_string())?;
+
+    Ok(over_limit(requests, left))
+}
+
+/// Limits requests with counts kept in Redis
+pub struct RedisRateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RedisRateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RedisRateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RedisRateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RedisRateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RedisRateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            let ip = match req.peer_addr() {
+                Some(address) => address.ip(),
+                None => return Ok(service.call(req).await?.map_into_left_body()),
+            };
+
+            let counted = web::block(move || count(ip))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|counted| counted);
+            let wait = match counted {
+                Ok(wait) => wait,
+                Err(e) => {
+                    log::error!("Failed to check the rate limit, letting the request through: {e}");
+                    None
+                }
+            };
+
+            match wait {
+                Some(wait) => {
+                    // rounded up, so retrying after it doesn't hit the limit again
+                    let wait = wait.div_ceil(1000);
+                    log::info!("Rate limit exceeded for {ip}, quota reset in {wait}s");
+                    let response = HttpResponse::TooManyRequests()
+                        .insert_header(("x-ratelimit-after", wait))
+                        .content_type(ContentType::plaintext())
+                        .body(format!("Too many requests, retry in {wait}s"));
+                    Ok(req.into_response(response).map_into_right_body())
+                }
+                None => Ok(service.call(req).await?.map_into_left_body()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn window_allows_the_rate_governor_does() {
+        assert_eq!(
+            Duration::from_millis(window()) / BURST,
+            PERIOD,
+            "a window of BURST requests lasts BURST periods"
+        );
+    }
+
+    #[test]
+    fn over_limit_lets_a_burst_through() {
+        for requests in 1..=BURST as u64 {
+            assert_eq!(over_limit(requests, 1000), None);
+        }
+        assert_eq!(over_limit(BURST as u64 + 1, 1000), Some(1000));
+    }
+
+    #[test]
+    fn over_limit_never_waits_a_negative_time() {
+        // PTTL answers -1 and -2 for keys without an expiry or gone
+        assert_eq!(over_limit(BURST as u64 + 1, -2), Some(0));
+    }
+
+    #[test]
+    #[ignore = "needs REDIS_URL pointing at a Redis server"]
+    fn count_limits_each_window() {
+        redis_pool::init();
+        let ip = IpAddr::V6(Ipv6Addr::new(
+            0x2001,
+            0xdb8,
+            0,
+            0,
+            0,
+            0,
+            rand::random(),
+            rand::random(),
+        ));
+
+        for _ in 0..BURST {
+            assert_eq!(count(ip).unwrap(), None);
+        }
+        let wait = count(ip).unwrap().expect("over the limit");
+        assert!(wait <= window());
+
+        std::thread::sleep(Duration::from_millis(wait + 100));
+        assert_eq!(count(ip).unwrap(), None);
+    }
+}
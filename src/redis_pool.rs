@@ -0,0 +1,35 @@
+//! Connections to the Redis that replicas share rate limits and note events
+//! through, when `REDIS_URL` is set.
+use std::{sync::OnceLock, time::Duration};
+
+// a request waits this long for a connection before the check is skipped
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub type Pool = r2d2::Pool<redis::Client>;
+
+static POOL: OnceLock<Option<(redis::Client, Pool)>> = OnceLock::new();
+
+/// Connects to Redis if it's set up, panicking when it can't be reached so a
+/// replica doesn't quietly fall back to limits of its own
+pub fn init() {
+    POOL.get_or_init(|| {
+        let url = std::env::var("REDIS_URL").ok()?;
+        let client = redis::Client::open(url).expect("REDIS_URL must be a redis:// url");
+        let pool = r2d2::Pool::builder()
+            .connection_timeout(CONNECTION_TIMEOUT)
+            .build(client.clone())
+            .expect("Failed to connect to REDIS_URL");
+        Some((client, pool))
+    });
+}
+
+pub fn pool() -> Option<&'static Pool> {
+    POOL.get().and_then(Option::as_ref).map(|(_, pool)| pool)
+}
+
+/// For connections of their own, like pub/sub subscriptions
+pub fn client() -> Option<&'static redis::Client> {
+    POOL.get()
+        .and_then(Option::as_ref)
+        .map(|(client, _)| client)
+}
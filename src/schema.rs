@@ -1,3 +1,83 @@
+table! {
+    dropboxes (id) {
+        id -> Varchar,
+        recipient_handle -> Nullable<Varchar>,
+        public_key -> Varchar,
+        wrapped_identity -> Nullable<Bytea>,
+        content -> Nullable<Bytea>,
+        submitted_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        delete_after_read -> Nullable<Int4>,
+    }
+}
+
+table! {
+    email_notifications (id) {
+        id -> Int4,
+        note_id -> Varchar,
+        recipient -> Bytea,
+        subject -> Varchar,
+        body -> Text,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+        sent_at -> Nullable<Timestamp>,
+        failed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    note_attachments (id) {
+        id -> Int4,
+        note_id -> Varchar,
+        name -> Varchar,
+        mime_type -> Varchar,
+        size -> Int4,
+        content -> Bytea,
+        created_at -> Timestamp,
+        content_blob -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    note_key_slots (id) {
+        id -> Int4,
+        note_id -> Varchar,
+        wrapped_key -> Bytea,
+        created_at -> Timestamp,
+        duress -> Bool,
+    }
+}
+
+table! {
+    note_recipients (note_id, recipient_handle) {
+        note_id -> Varchar,
+        recipient_handle -> Varchar,
+    }
+}
+
+table! {
+    note_tags (note_id, tag) {
+        note_id -> Varchar,
+        tag -> Varchar,
+    }
+}
+
+table! {
+    note_versions (id) {
+        id -> Int4,
+        note_id -> Varchar,
+        content -> Bytea,
+        content_blob -> Nullable<Varchar>,
+        content_format -> Int2,
+        frontend_envelope -> Nullable<Jsonb>,
+        written_at -> Timestamp,
+        replaced_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     notes (id) {
         id -> Varchar,
@@ -10,5 +90,84 @@ table! {
         expires_at -> Nullable<Timestamp>,
         delete_after_read -> Nullable<Int4>,
         allow_delete_with_passphrase -> Bool,
+        decoy_content -> Nullable<Bytea>,
+        destroy_on_duress -> Bool,
+        recipient_encryption -> Bool,
+        frontend_envelope -> Nullable<Jsonb>,
+        content_blob -> Nullable<Varchar>,
+        decoy_blob -> Nullable<Varchar>,
+        content_format -> Int2,
+        decoy_format -> Int2,
+        content_type -> Varchar,
+        language -> Nullable<Varchar>,
+        edited_at -> Nullable<Timestamp>,
+        search_text -> Nullable<Text>,
+        view_count -> Int8,
+        metadata_view_count -> Int8,
+        first_read_at -> Nullable<Timestamp>,
+        last_read_at -> Nullable<Timestamp>,
+        webhook_url -> Nullable<Varchar>,
+        webhook_secret -> Nullable<Varchar>,
+        notify_email -> Nullable<Bytea>,
+    }
+}
+
+table! {
+    recipients (handle) {
+        handle -> Varchar,
+        public_key -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    upload_chunks (upload_id, chunk_offset) {
+        upload_id -> Varchar,
+        chunk_offset -> Int8,
+        data -> Bytea,
+    }
+}
+
+table! {
+    uploads (id) {
+        id -> Varchar,
+        upload_length -> Int8,
+        upload_offset -> Int8,
+        metadata -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        note_id -> Varchar,
+        url -> Varchar,
+        secret -> Varchar,
+        event -> Varchar,
+        payload -> Text,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
+        failed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
     }
 }
+
+joinable!(dropboxes -> recipients (recipient_handle));
+joinable!(note_attachments -> notes (note_id));
+joinable!(note_key_slots -> notes (note_id));
+joinable!(note_recipients -> notes (note_id));
+joinable!(note_recipients -> recipients (recipient_handle));
+joinable!(note_tags -> notes (note_id));
+joinable!(note_versions -> notes (note_id));
+joinable!(upload_chunks -> uploads (upload_id));
+
+allow_tables_to_appear_in_same_query!(
+    note_key_slots,
+    note_recipients,
+    note_tags,
+    notes,
+    recipients,
+);
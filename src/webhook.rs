@@ -0,0 +1,569 @@
+//! Read receipts POSTed to a URL the owner of a note gave when creating it.
+//!
+//! Receipts are queued in `webhook_deliveries` and sent by a worker thread,
+//! which retries failed ones with exponential backoff. Every request carries
+//! an HMAC-SHA256 of `"{timestamp}.{body}"` under the secret of the note:
+//!
+//! ```text
+//! X-Himitsu-Event: note.read
+//! X-Himitsu-Delivery: 42
+//! X-Himitsu-Timestamp: 1792361258
+//! X-Himitsu-Signature: sha256=<hex>
+//! ```
+//!
+//! Hosts resolving to loopback or private addresses are refused unless
+//! `WEBHOOK_ALLOW_PRIVATE_HOSTS=true`, so notes can't be used to probe the
+//! network the server sits in.
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{mpsc, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use diesel::{pg::PgConnection, prelude::*};
+use nanoid::nanoid;
+use ring::hmac;
+use serde_json::json;
+
+use crate::{
+    errors::ServerError,
+    handlers::Pool,
+    schema::{notes, webhook_deliveries},
+};
+
+pub const EVENT_READ: &str = "note.read";
+
+const MAX_URL_LENGTH: usize = 2048;
+const SECRET_LENGTHS: std::ops::RangeInclusive<usize> = 16..=256;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BASE_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+const BATCH_SIZE: i64 = 20;
+// how much longer than a request may take a claimed receipt is left to the
+// worker that claimed it, before another one may try it again
+const CLAIM_MARGIN: Duration = Duration::from_secs(60);
+// delivered and abandoned receipts are kept this long for debugging
+const KEEP_FINISHED: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+struct Settings {
+    max_attempts: i32,
+    timeout: Duration,
+    allow_private_hosts: bool,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+static WAKE: OnceLock<mpsc::Sender<()>> = OnceLock::new();
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(|| Settings {
+        max_attempts: std::env::var("WEBHOOK_MAX_ATTEMPTS")
+            .unwrap_or("8".to_string())
+            .parse::<i32>()
+            .expect("must be a number of attempts"),
+        timeout: Duration::from_secs(
+            std::env::var("WEBHOOK_TIMEOUT")
+                .unwrap_or("10".to_string())
+                .parse::<u64>()
+                .expect("must be an unsigned number of seconds"),
+        ),
+        allow_private_hosts: std::env::var("WEBHOOK_ALLOW_PRIVATE_HOSTS")
+            .map(|v| v == "true")
+            .unwrap_or(false),
+    })
+}
+
+/// Checks a webhook URL given for a note, telling what's wrong with it
+pub fn validate_url(raw: &str) -> Result<(), String> {
+    if raw.len() > MAX_URL_LENGTH {
+        return Err(format!(
+            "webhook url is longer than {MAX_URL_LENGTH} characters"
+        ));
+    }
+    let url = url::Url::parse(raw).map_err(|e| format!("webhook url is not valid: {e}"))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err("webhook url must be http or https".to_string());
+    }
+    if url.host().is_none() {
+        return Err("webhook url has no host".to_string());
+    }
+    Ok(())
+}
+
+pub fn validate_secret(secret: &str) -> Result<(), String> {
+    if !SECRET_LENGTHS.contains(&secret.len()) {
+        return Err(format!(
+            "webhook secret must be {} to {} characters",
+            SECRET_LENGTHS.start(),
+            SECRET_LENGTHS.end()
+        ));
+    }
+    Ok(())
+}
+
+/// For webhooks given without a secret
+pub fn new_secret() -> String {
+    nanoid!(32)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // unique local and link local
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Queues a receipt for a read of the note, if it has a webhook
+pub fn enqueue_read(connection: &mut PgConnection, nid: &str) -> Result<(), ServerError> {
+    let (url, secret) = notes::table
+        .select((notes::webhook_url, notes::webhook_secret))
+        .find(nid)
+        .first::<(Option<String>, Option<String>)>(connection)?;
+    let (url, secret) = match (url, secret) {
+        (Some(url), Some(secret)) => (url, secret),
+        _ => return Ok(()),
+    };
+
+    let payload = json!({
+        "event": EVENT_READ,
+        "note_id": nid,
+        "read_at": SystemTime::now(),
+    });
+    diesel::insert_into(webhook_deliveries::table)
+        .values((
+            webhook_deliveries::note_id.eq(nid),
+            webhook_deliveries::url.eq(url),
+            webhook_deliveries::secret.eq(secret),
+            webhook_deliveries::event.eq(EVENT_READ),
+            webhook_deliveries::payload.eq(payload.to_string()),
+        ))
+        .execute(connection)?;
+
+    // the worker gets to it right away instead of on its next round
+    if let Some(wake) = WAKE.get() {
+        wake.send(()).ok();
+    }
+    Ok(())
+}
+
+#[derive(Queryable)]
+struct Delivery {
+    id: i32,
+    url: String,
+    secret: String,
+    event: String,
+    payload: String,
+    attempts: i32,
+}
+
+fn signature(secret: &str, timestamp: u64, payload: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, format!("{timestamp}.{payload}").as_bytes());
+    tag.as_ref().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn send(agent: &ureq::Agent, delivery: &Delivery) -> Result<(), String> {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let response = agent
+        .post(&delivery.url)
+        .set("Content-Type", "application/json")
+        .set("User-Agent", "himitsu-webhook")
+        .set("X-Himitsu-Event", &delivery.event)
+        .set("X-Himitsu-Delivery", &delivery.id.to_string())
+        .set("X-Himitsu-Timestamp", &timestamp.to_string())
+        .set(
+            "X-Himitsu-Signature",
+            &format!(
+                "sha256={}",
+                signature(&delivery.secret, timestamp, &delivery.payload)
+            ),
+        )
+        .send_string(&delivery.payload)
+        .map_err(|e| e.to_string())?;
+
+    // redirects aren't followed, they could lead anywhere
+    match response.status() {
+        200..=299 => Ok(()),
+        status => Err(format!("responded with {status}")),
+    }
+}
+
+/// How long to wait before the next attempt, after `attempts` failed ones
+pub fn backoff(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    BASE_BACKOFF
+        .saturating_mul(2u32.pow(exponent))
+        .min(MAX_BACKOFF)
+}
+
+/// Takes the next receipt that's due. Its next attempt is pushed past the
+/// time sending it may take, so workers on other replicas leave it alone.
+fn claim_next(connection: &mut PgConnection) -> Result<Option<Delivery>, ServerError> {
+    connection.transaction(|connection| {
+        let delivery = webhook_deliveries::table
+            .select((
+                webhook_deliveries::id,
+                webhook_deliveries::url,
+                webhook_deliveries::secret,
+                webhook_deliveries::event,
+                webhook_deliveries::payload,
+                webhook_deliveries::attempts,
+            ))
+            .filter(webhook_deliveries::delivered_at.is_null())
+            .filter(webhook_deliveries::failed_at.is_null())
+            .filter(webhook_deliveries::next_attempt_at.le(SystemTime::now()))
+            .order(webhook_deliveries::next_attempt_at.asc())
+            .for_update()
+            .skip_locked()
+            .first::<Delivery>(connection)
+            .optional()?;
+
+        if let Some(delivery) = &delivery {
+            diesel::update(webhook_deliveries::table.find(delivery.id))
+                .set(
+                    webhook_deliveries::next_attempt_at
+                        .eq(SystemTime::now() + settings().timeout + CLAIM_MARGIN),
+                )
+                .execute(connection)?;
+        }
+        Ok(delivery)
+    })
+}
+
+/// Sends every receipt that's due, up to a batch, returning how many went out
+fn deliver_due(connection: &mut PgConnection, agent: &ureq::Agent) -> Result<usize, ServerError> {
+    let mut delivered = 0;
+    for _ in 0..BATCH_SIZE {
+        let delivery = match claim_next(connection)? {
+            Some(delivery) => delivery,
+            None => break,
+        };
+        let attempts = delivery.attempts + 1;
+        let now = SystemTime::now();
+        let row = webhook_deliveries::table.find(delivery.id);
+
+        match send(agent, &delivery) {
+            Ok(()) => {
+                diesel::update(row)
+                    .set((
+                        webhook_deliveries::attempts.eq(attempts),
+                        webhook_deliveries::delivered_at.eq(now),
+                    ))
+                    .execute(connection)?;
+                delivered += 1;
+            }
+            Err(reason) if attempts >= settings().max_attempts => {
+                log::warn!(
+                    "Giving up on webhook delivery {} after {attempts} attempts: {reason}",
+                    delivery.id
+                );
+                diesel::update(row)
+                    .set((
+                        webhook_deliveries::attempts.eq(attempts),
+                        webhook_deliveries::failed_at.eq(now),
+                    ))
+                    .execute(connection)?;
+            }
+            Err(reason) => {
+                log::info!(
+                    "Webhook delivery {} failed, retrying: {reason}",
+                    delivery.id
+                );
+                diesel::update(row)
+                    .set((
+                        webhook_deliveries::attempts.eq(attempts),
+                        webhook_deliveries::next_attempt_at.eq(now + backoff(attempts)),
+                    ))
+                    .execute(connection)?;
+            }
+        }
+    }
+
+    Ok(delivered)
+}
+
+/// What receipts are sent with, refusing hosts that aren't public unless
+/// `allow_private_hosts`
+fn agent(allow_private_hosts: bool) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(settings().timeout)
+        .redirects(0)
+        .resolver(move |netloc: &str| -> io::Result<Vec<SocketAddr>> {
+            let addresses = netloc
+                .to_socket_addrs()?
+                .filter(|address| allow_private_hosts || is_public(address.ip()))
+                .collect::<Vec<_>>();
+            if addresses.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{netloc} is not a public address"),
+                ));
+            }
+            Ok(addresses)
+        })
+        .build()
+}
+
+/// Starts the thread sending receipts
+pub fn spawn_worker(pool: Pool) {
+    let (wake, woken) = mpsc::channel();
+    WAKE.set(wake).ok();
+
+    let agent = agent(settings().allow_private_hosts);
+    std::thread::spawn(move || loop {
+        match pool.get() {
+            Ok(mut connection) => match deliver_due(&mut connection, &agent) {
+                Ok(0) => (),
+                Ok(delivered) => log::info!("Delivered {delivered} webhooks"),
+                Err(e) => log::error!("Failed to deliver webhooks: {e}"),
+            },
+            Err(e) => log::error!("Failed to deliver webhooks: {e}"),
+        }
+
+        woken.recv_timeout(POLL_INTERVAL).ok();
+        while woken.try_recv().is_ok() {}
+    });
+}
+
+/// Deletes receipts that went out or were given up on a while ago, returning
+/// how many were deleted
+pub fn remove_finished(connection: &mut PgConnection) -> Result<usize, ServerError> {
+    let cutoff = SystemTime::now() - KEEP_FINISHED;
+    Ok(diesel::delete(
+        webhook_deliveries::table.filter(
+            webhook_deliveries::delivered_at
+                .le(cutoff)
+                .or(webhook_deliveries::failed_at.le(cutoff)),
+        ),
+    )
+    .execute(connection)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn signature_is_hmac_sha256_of_timestamp_and_payload() {
+        assert_eq!(
+            signature(
+                "supersecretvalue1234",
+                1792361258,
+                r#"{"event":"note.read"}"#
+            ),
+            "4f07098815c81ba73c2b53b0c264b7e9e9646ab307b49d5d2cc283df1c0c041e"
+        );
+    }
+
+    #[test]
+    fn signature_changes_with_the_timestamp() {
+        assert_ne!(
+            signature("supersecretvalue1234", 1, "{}"),
+            signature("supersecretvalue1234", 2, "{}")
+        );
+    }
+
+    #[test]
+    fn is_public_refuses_local_and_private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} is not public");
+        }
+    }
+
+    #[test]
+    fn is_public_allows_public_addresses() {
+        for ip in [
+            "1.1.1.1",
+            "100.128.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip} is public");
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff(0), BASE_BACKOFF);
+        assert_eq!(backoff(1), BASE_BACKOFF);
+        assert_eq!(backoff(2), BASE_BACKOFF * 2);
+        assert_eq!(backoff(4), BASE_BACKOFF * 8);
+        assert_eq!(backoff(30), MAX_BACKOFF);
+        assert_eq!(backoff(i32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn agent_refuses_private_hosts() {
+        let error = agent(false)
+            .post("http://127.0.0.1:9/")
+            .send_string("{}")
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("not a public address"),
+            "{error}"
+        );
+    }
+
+    /// Answers one request with `status`, handing back its headers and body
+    fn stub(listener: TcpListener, status: u16) -> std::thread::JoinHandle<(Vec<String>, String)> {
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut headers = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_lowercase());
+            }
+            let length = headers
+                .iter()
+                .find_map(|header| header.strip_prefix("content-length: "))
+                .and_then(|length| length.parse::<usize>().ok())
+                .unwrap_or_default();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {status} Stub\r\ncontent-length: 0\r\n\r\n"
+            )
+            .unwrap();
+            (headers, String::from_utf8(body).unwrap())
+        })
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL pointing at a migrated database"]
+    fn deliver_due_sends_signed_receipts_once() {
+        let mut connection =
+            PgConnection::establish(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = stub(listener, 204);
+
+        let delivery = diesel::insert_into(webhook_deliveries::table)
+            .values((
+                webhook_deliveries::note_id.eq(nanoid!()),
+                webhook_deliveries::url.eq(&url),
+                webhook_deliveries::secret.eq("supersecretvalue1234"),
+                webhook_deliveries::event.eq(EVENT_READ),
+                webhook_deliveries::payload.eq(r#"{"event":"note.read"}"#),
+            ))
+            .returning(webhook_deliveries::id)
+            .get_result::<i32>(&mut connection)
+            .unwrap();
+
+        let agent = agent(true);
+        deliver_due(&mut connection, &agent).unwrap();
+        let (headers, body) = received.join().unwrap();
+
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find_map(|header| header.strip_prefix(&format!("{name}: ")))
+                .unwrap()
+                .to_string()
+        };
+        let timestamp = header("x-himitsu-timestamp").parse::<u64>().unwrap();
+        assert_eq!(body, r#"{"event":"note.read"}"#);
+        assert_eq!(header("x-himitsu-delivery"), delivery.to_string());
+        assert_eq!(
+            header("x-himitsu-signature"),
+            format!(
+                "sha256={}",
+                signature("supersecretvalue1234", timestamp, &body)
+            )
+        );
+
+        let (attempts, delivered) = webhook_deliveries::table
+            .select((
+                webhook_deliveries::attempts,
+                webhook_deliveries::delivered_at.is_not_null(),
+            ))
+            .find(delivery)
+            .first::<(i32, bool)>(&mut connection)
+            .unwrap();
+        assert_eq!((attempts, delivered), (1, true));
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL pointing at a migrated database"]
+    fn deliver_due_backs_off_after_a_failure() {
+        let mut connection =
+            PgConnection::establish(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = stub(listener, 500);
+
+        let delivery = diesel::insert_into(webhook_deliveries::table)
+            .values((
+                webhook_deliveries::note_id.eq(nanoid!()),
+                webhook_deliveries::url.eq(&url),
+                webhook_deliveries::secret.eq("supersecretvalue1234"),
+                webhook_deliveries::event.eq(EVENT_READ),
+                webhook_deliveries::payload.eq("{}"),
+            ))
+            .returning(webhook_deliveries::id)
+            .get_result::<i32>(&mut connection)
+            .unwrap();
+
+        let before = SystemTime::now();
+        deliver_due(&mut connection, &agent(true)).unwrap();
+        received.join().unwrap();
+
+        let (attempts, next_attempt, delivered) = webhook_deliveries::table
+            .select((
+                webhook_deliveries::attempts,
+                webhook_deliveries::next_attempt_at,
+                webhook_deliveries::delivered_at.is_not_null(),
+            ))
+            .find(delivery)
+            .first::<(i32, SystemTime, bool)>(&mut connection)
+            .unwrap();
+        assert_eq!((attempts, delivered), (1, false));
+        assert!(next_attempt >= before + backoff(1));
+    }
+}